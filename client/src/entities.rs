@@ -0,0 +1,104 @@
+use std::{collections::HashMap, sync::{Mutex, mpsc::{self, Receiver, TryRecvError}}, thread};
+use crate::game::InterpolatePosition;
+use bevy::prelude::*;
+use game_structs::{ServerAddress, entities::{EntityKind, EntityUpdate, ReplicatedEntity}, sequence::SequenceTracker};
+use uuid::Uuid;
+
+use crate::multiplayer::server_address;
+
+/// Move entities to where servers last told us they are
+pub fn sync_entity_transforms(mut entity_query: Query<(&ReplicatedEntity, &mut Transform, &mut InterpolatePosition)>,
+    receiver: Res<crate::EntityUpdates>,
+    mut sequences: Local<SequenceTracker>
+) {
+    // Keep the newest update for each entity, dropping any that arrived late
+    let mut updates: HashMap<Uuid, EntityUpdate> = HashMap::new();
+    let receiver = receiver.0.lock().unwrap();
+    while let Ok(update) = receiver.try_recv() {
+        if sequences.accept(update.id, update.seq) {
+            updates.insert(update.id, update);
+        }
+    }
+
+    for (entity, mut transform, mut interpolate_position) in entity_query.iter_mut() {
+        if let Some(update) = updates.remove(&entity.id) {
+            interpolate_position.target = update.position;
+            transform.rotation = update.rotation;
+        }
+    }
+}
+
+/// Entities on the servers we are on, and whether every server answered
+type EntityListing = (HashMap<Uuid, ReplicatedEntity>, bool);
+
+/// Ask each server for its entities, leaving out any that don't answer
+fn fetch_entities(addresses: Vec<ServerAddress>) -> EntityListing {
+    let mut entities = HashMap::new();
+    let mut complete = true;
+    for address in addresses {
+        match reqwest::blocking::get(format!("{}/get_entities", address.http)).and_then(|r| r.json::<Vec<ReplicatedEntity>>()) {
+            Ok(listed) => entities.extend(listed.into_iter().map(|e| (e.id, e))),
+            Err(_) => complete = false, // Restarting or terminated, try again next time
+        }
+    }
+    (entities, complete)
+}
+
+/// Sync entities from the servers we are on, catching anything the reliable events haven't told us about yet.
+/// Servers are asked on another thread so a slow one doesn't hold up the frame, and what they said is applied on the next run
+pub fn sync_entities(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    server: Res<crate::Server>,
+    server_addresses: Res<crate::ServerAddresses>,
+    mut entity_query: Query<(Entity, &mut ReplicatedEntity)>,
+    mut fetching: Local<Option<Mutex<Receiver<EntityListing>>>>
+) {
+    let listing = match fetching.as_ref().map(|f| f.lock().unwrap().try_recv()) {
+        None => None,
+        Some(Err(TryRecvError::Empty)) => return, // Still waiting on the last one
+        Some(result) => result.ok(),
+    };
+
+    // Start asking again
+    let addresses: Vec<ServerAddress> = {
+        let server_addresses = server_addresses.0.lock().unwrap();
+        server.0.lock().unwrap().iter().filter_map(|s| server_address(&server_addresses, *s).cloned()).collect()
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(fetch_entities(addresses));
+    });
+    *fetching = Some(Mutex::new(receiver));
+
+    let (mut entities, complete) = match listing {
+        Some(l) => l,
+        None => return,
+    };
+    for (entity, mut replicated) in entity_query.iter_mut() {
+        match entities.remove(&replicated.id) {
+            Some(e) => replicated.data = e.data, // Positions come from the entity updates
+            None if complete => commands.entity(entity).despawn(),
+            None => {}, // Might be on a server that didn't answer
+        }
+    }
+    for (_, entity) in entities {
+        spawn_entity(&mut commands, &mut meshes, &mut materials, entity);
+    }
+}
+
+/// Spawn an entity, drawn according to its kind
+pub fn spawn_entity(commands: &mut Commands, meshes: &mut Assets<Mesh>, materials: &mut Assets<StandardMaterial>, entity: ReplicatedEntity) {
+    let (mesh, color) = match entity.kind {
+        EntityKind::Npc => (Mesh::from(shape::Cube { size: 1.0 }), Color::rgb(0.2, 0.8, 0.2)),
+        EntityKind::Projectile => (Mesh::from(shape::Icosphere { radius: 0.2, subdivisions: 2 }), Color::rgb(1.0, 0.6, 0.0)),
+        EntityKind::Item => (Mesh::from(shape::Cube { size: 0.4 }), Color::rgb(1.0, 1.0, 0.2)),
+        EntityKind::Other(_) => (Mesh::from(shape::Cube { size: 0.5 }), Color::rgb(0.6, 0.6, 0.6)),
+    };
+    commands.spawn_bundle(PbrBundle {
+        mesh: meshes.add(mesh),
+        material: materials.add(color.into()),
+        transform: Transform::from_translation(entity.position).with_rotation(entity.rotation),
+        ..Default::default()
+    }).insert(InterpolatePosition{target: entity.position})
+    .insert(entity);
+}
@@ -28,7 +28,7 @@ pub fn setup(
         material: materials.add(Color::rgb(0.0, 0.2, 1.0).into()),
         transform: Transform::from_xyz(2.0, 0.5, 0.0),
         ..Default::default()
-    }).insert(Player{id:player.id})
+    }).insert(player.clone())
     .insert(CurrentPlayer{});
     // light
     commands.spawn_bundle(PointLightBundle {
@@ -79,12 +79,15 @@ pub fn interpolate_positions(mut query: Query<(&mut Transform, &InterpolatePosit
     }
 }
 
-pub fn exit_system(keys: Res<Input<KeyCode>>, player: Res<Player>, mut exit: EventWriter<AppExit>, server: Res<crate::Server>) {
+pub fn exit_system(keys: Res<Input<KeyCode>>, player: Res<Player>, player_token: Res<crate::PlayerToken>, mut exit: EventWriter<AppExit>, server: Res<crate::Server>, server_addresses: Res<crate::ServerAddresses>) {
     for key in keys.get_pressed() {
         if *key == KeyCode::Escape {
             let servers = server.0.lock().unwrap();
+            let server_addresses = server_addresses.0.lock().unwrap();
             for server in servers.iter() {
-                crate::multiplayer::send_exit_to_server(player.id, *server);
+                if let Some(address) = crate::multiplayer::server_address(&server_addresses, *server) {
+                    crate::multiplayer::send_exit_to_server(player.id, &player_token.0, address);
+                }
             }
             exit.send(AppExit);
         }
@@ -1,21 +1,31 @@
+mod entities;
 mod game;
 mod multiplayer;
 
 use clap::Parser;
-use std::{sync::{mpsc::{Sender, Receiver, self}, Mutex}, net::UdpSocket, thread, collections::HashSet};
+use std::{sync::{mpsc::{Sender, Receiver, self}, Mutex, Arc, atomic::AtomicU32}, net::UdpSocket, thread, io, collections::{HashMap, HashSet}, time::Duration};
 use bevy::{prelude::*, core::FixedTimestep};
 use game_structs::{
     Player,
+    ServerAddress,
+    attributes::{AttributeValue, NAME, MAX_TEXT_LENGTH},
+    routing::RoutingTable,
+    encoding::{CompactDecoder, Encoding, Quantizer},
+    entities::EntityUpdate,
+    reliable::ReliableEndpoint,
+    sequence::SequenceTracker,
     operations::{
         PositionUpdate,
-        PlayerRegister
+        PlayerRegister,
+        PlayerRegistered,
+        GameEvent
     }
 };
 use uuid::Uuid;
 
-static SERVER_ADDRESSES: [&str; 2] = ["http://127.0.0.1:8000", "http://127.0.0.1:8001"];
-static SERVER_RECEIVING_PORTS: [&str; 2] = ["127.0.0.1:41794", "127.0.0.1:47810"];
 static COORD_SERVER_ADDRESS: &str = "http://127.0.0.1:8002";
+static COORD_QUERY_ADDRESS: &str = "127.0.0.1:8003"; // Where the coordination server answers binary server lookups
+static COORD_QUERY_TIMEOUT: Duration = Duration::from_millis(500); // How long to wait for a lookup before trying again next sync
 
 fn main() {
     let args = Args::parse();
@@ -24,47 +34,115 @@ fn main() {
     // Create sockets to send/recv updates with
     let send_socket = UdpSocket::bind(format!("127.0.0.1:{}", args.send)).expect("Failed to bind send socket");
     let receive_socket = UdpSocket::bind(format!("127.0.0.1:{}", args.receive)).expect("Failed to bind receive socket");
+    let coord_socket = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind coord query socket");
+    coord_socket.connect(COORD_QUERY_ADDRESS).expect("Failed to connect to coord query socket");
+    coord_socket.set_read_timeout(Some(COORD_QUERY_TIMEOUT)).unwrap();
     
     // Create position update collector thread
+    let handoff_notice = Arc::new(Mutex::new(None));
+    let handoff_notice1 = handoff_notice.clone();
+    let sender1 = sender.clone();
+    let decoders = Arc::new(Mutex::new(HashMap::new()));
+    let decoders1 = decoders.clone();
+    let endpoints = Arc::new(Mutex::new(HashMap::new()));
+    let endpoints1 = endpoints.clone();
+    let (event_sender, event_receiver): (Sender<GameEvent>, Receiver<GameEvent>) = mpsc::channel();
+    let (entity_sender, entity_receiver): (Sender<EntityUpdate>, Receiver<EntityUpdate>) = mpsc::channel();
+    let (correction_sender, correction_receiver): (Sender<PositionUpdate>, Receiver<PositionUpdate>) = mpsc::channel();
     let collector_thread_handle = thread::spawn(move || {
-        multiplayer::capture_changes(sender, receive_socket);
+        multiplayer::capture_changes(sender1, handoff_notice1, decoders1, endpoints1, event_sender, entity_sender, correction_sender, receive_socket);
+    });
+
+    // Read chat typed into the terminal, a line at a time
+    let (chat_sender, chat_receiver): (Sender<String>, Receiver<String>) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if chat_sender.send(line).is_err() {break;}
+        }
     });
     
+    // Find out where the servers are
+    let server_addresses = multiplayer::fetch_server_addresses();
+    let routing = if args.remote_lookup {None} else {Some(multiplayer::fetch_routing_table().expect("Failed to download routing table"))};
+
+    // Offer the compact encoding unless told not to
+    let encodings = if args.bincode {vec![Encoding::Bincode]} else {vec![Encoding::Compact, Encoding::Bincode]};
+
     // Create player
-    let mut player = Player {id: Uuid::default()};
-    player.id = reqwest::blocking::Client::new().post("http://127.0.0.1:8000/register_player").header("Content-Type", "application/json")
+    let mut player = Player::new(Uuid::default());
+    if let Some(name) = &args.name {
+        player.attributes.insert(NAME.to_string(), AttributeValue::Text(name.chars().take(MAX_TEXT_LENGTH).collect())); // Longer names aren't accepted
+    }
+    let registered: PlayerRegistered = reqwest::blocking::Client::new().post(format!("{}/register_player", server_addresses[0].as_ref().expect("First server is not running").http)).header("Content-Type", "application/json")
         .body(serde_json::to_string(
             &PlayerRegister {
                 player: player.clone(),
-                address: format!("127.0.0.1:{}", args.receive)
+                address: format!("127.0.0.1:{}", args.receive),
+                encodings: encodings.clone()
             }
         ).unwrap())
         .send().unwrap()
         .json().unwrap();
+    player.id = registered.player_id;
+    let server_sessions = ServerSessions {
+        sessions: Mutex::new(HashMap::new()),
+        decoders,
+        endpoints,
+        encodings,
+    };
+    server_sessions.insert(0, &registered);
+    for update in registered.snapshot {
+        sender.send(update).expect("Failed to put update in queue");
+    }
 
     App::new()
         .insert_resource(Msaa { samples: 4 })
         .insert_resource(player)
+        .insert_resource(PlayerToken(registered.token))
         .insert_resource(Mutex::new(receiver))
         .insert_resource(send_socket)
         .insert_resource(Server(Mutex::new(vec![0_usize].into_iter().collect())))
+        .insert_resource(ServerAddresses(Mutex::new(server_addresses)))
         .insert_resource(ReceivePort(args.receive))
+        .insert_resource(HandoffNotice(handoff_notice))
+        .insert_resource(CoordSocket(coord_socket, AtomicU32::new(0)))
+        .insert_resource(Routing(Mutex::new(routing)))
+        .insert_resource(PositionSender(Mutex::new(sender)))
+        .insert_resource(server_sessions)
+        .insert_resource(GameEvents(Mutex::new(event_receiver)))
+        .insert_resource(EntityUpdates(Mutex::new(entity_receiver)))
+        .insert_resource(Corrections(Mutex::new(correction_receiver)))
+        .insert_resource(UpdateSequences(Mutex::new(SequenceTracker::default())))
+        .insert_resource(ChatInput(Mutex::new(chat_receiver)))
         .add_plugins(DefaultPlugins)
         .add_startup_system(game::setup.system())
         .add_system(game::move_block.system())
         .add_system(game::interpolate_positions.system())
         .add_system(game::exit_system.system())
+        .add_system(multiplayer::handle_handoffs.system())
+        .add_system(multiplayer::handle_events.system())
+        .add_system(multiplayer::apply_corrections.system())
+        .add_system(multiplayer::send_actions.system())
+        .add_system(multiplayer::send_chat.system())
+        .add_system(entities::sync_entity_transforms.system())
         .add_stage("position_sync", SystemStage::parallel()
             .with_run_criteria(FixedTimestep::steps_per_second(20.0))
             .with_system(multiplayer::sync_positions.system())
+            .with_system(multiplayer::sync_reliable.system())
         )
         .add_stage("player_sync", SystemStage::parallel()
             .with_run_criteria(FixedTimestep::steps_per_second(4.0))
             .with_system(multiplayer::sync_players.system())
+            .with_system(entities::sync_entities.system())
         )
         .add_stage("server_sync", SystemStage::parallel()
             .with_run_criteria(FixedTimestep::steps_per_second(1.0))
             .with_system(multiplayer::sync_servers.system())
+            .with_system(multiplayer::sync_routing.system())
+        )
+        .add_stage("stats", SystemStage::parallel()
+            .with_run_criteria(FixedTimestep::step(10.0))
+            .with_system(multiplayer::log_update_stats.system())
         )
         .run();
 
@@ -81,7 +159,66 @@ struct Args {
     /// The port to receive updates from the server
     #[clap(short, long)]
     receive: String,
+
+    /// Only offer servers the plain bincode encoding, rather than the compact one
+    #[clap(long)]
+    bincode: bool,
+
+    /// Name shown to other players
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Ask the coordination server for our servers instead of looking them up in a local copy of the routing table
+    #[clap(long)]
+    remote_lookup: bool,
 }
 
 pub struct Server(Mutex<HashSet<usize>>);
-pub struct ReceivePort(String);
\ No newline at end of file
+pub struct ServerAddresses(Mutex<Vec<Option<ServerAddress>>>);
+pub struct ReceivePort(String);
+pub struct PlayerToken(String);
+pub struct HandoffNotice(Arc<Mutex<Option<HashSet<usize>>>>); // Servers we have been told to move to
+pub struct CoordSocket(UdpSocket, AtomicU32); // Socket for binary server lookups, and the ID of the next lookup
+pub struct Routing(Mutex<Option<RoutingTable>>); // Local copy of the routing table, None when using remote lookups
+pub struct PositionSender(Mutex<Sender<PositionUpdate>>); // Lets systems queue position updates, such as snapshots from servers we join
+pub struct GameEvents(Mutex<Receiver<GameEvent>>); // Events handed over by the reliable channels to our servers
+pub struct EntityUpdates(Mutex<Receiver<EntityUpdate>>); // Entity transforms sent by our servers each tick
+pub struct Corrections(Mutex<Receiver<PositionUpdate>>); // Where our servers put us when they clamped one of our moves
+pub struct UpdateSequences(Mutex<SequenceTracker>); // Latest update we've had from each other player, so late ones can be dropped
+pub struct ChatInput(Mutex<Receiver<String>>); // Lines typed into the terminal, waiting to be sent
+
+/// What we agreed with a server when we registered
+pub struct ServerSession {
+    session_key: u64, // Sent with our updates so the server knows they are from us
+    encoding: Encoding,
+    quantizer: Quantizer,
+}
+
+/// Our registration with each server we are on
+pub struct ServerSessions {
+    sessions: Mutex<HashMap<usize, ServerSession>>,
+    decoders: Arc<Mutex<HashMap<u64, CompactDecoder>>>, // Compact streams by session key, shared with the collector thread
+    endpoints: Arc<Mutex<HashMap<u64, ReliableEndpoint>>>, // Reliable channels by session key, shared with the collector thread
+    encodings: Vec<Encoding>, // Encodings we offer servers, most preferred first
+}
+
+impl ServerSessions {
+    pub fn insert(&self, server: usize, registered: &PlayerRegistered) {
+        let session = ServerSession { session_key: registered.session_key, encoding: registered.encoding, quantizer: registered.quantizer };
+        if let Some(old) = self.sessions.lock().unwrap().insert(server, session) {
+            self.decoders.lock().unwrap().remove(&old.session_key);
+            self.endpoints.lock().unwrap().remove(&old.session_key);
+        }
+        self.endpoints.lock().unwrap().insert(registered.session_key, ReliableEndpoint::default());
+        if registered.encoding == Encoding::Compact {
+            self.decoders.lock().unwrap().insert(registered.session_key, CompactDecoder::new(registered.quantizer));
+        }
+    }
+
+    pub fn remove(&self, server: usize) {
+        if let Some(old) = self.sessions.lock().unwrap().remove(&server) {
+            self.decoders.lock().unwrap().remove(&old.session_key);
+            self.endpoints.lock().unwrap().remove(&old.session_key);
+        }
+    }
+}
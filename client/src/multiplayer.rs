@@ -1,7 +1,7 @@
-use std::{collections::{HashMap, HashSet}, sync::{mpsc::{Sender, Receiver}, Mutex}, net::UdpSocket};
+use std::{collections::{HashMap, HashSet}, sync::{mpsc::{Sender, Receiver}, Mutex, Arc, atomic::Ordering}, net::UdpSocket, time::Instant};
 use crate::game::InterpolatePosition;
 use bevy::prelude::*;
-use game_structs::{Player, operations::{PositionUpdate, PlayerRegister}};
+use game_structs::{Player, ServerAddress, attributes::{self, Attributes, AttributeValue, COLOR, NAME}, entities::{EntityUpdate, ReplicatedEntity}, auth::AUTH_HEADER, encoding::{CompactDecoder, CompactUpdate, Encoding}, operations::{ClientMessage, ClientUpdate, PositionUpdate, PlayerRegister, PlayerRegistered, PlayerTransfer, ServerMessage, CoordRequest, CoordResponse, GameEvent, ChatScope, ACTION_CHANNEL, CHAT_CHANNEL, MAX_CHAT_LENGTH}, reliable::{ReliableEndpoint, Delivery}, sequence::timestamp_now, routing::{RoutingTable, RoutingUpdate}};
 use uuid::Uuid;
 
 #[allow(clippy::too_many_arguments)]
@@ -12,17 +12,24 @@ pub fn sync_positions(
     socket: Res<UdpSocket>,
     receiver: Res<Mutex<Receiver<PositionUpdate>>>,
     server: Res<crate::Server>,
+    server_addresses: Res<crate::ServerAddresses>,
+    server_sessions: Res<crate::ServerSessions>,
+    sequences: Res<crate::UpdateSequences>,
+    mut position_updates: Local<HashMap<Uuid, PositionUpdate>>,
+    mut seq: Local<u32>,
 ) {
     let current_player_transform = main_player_query.iter().next().unwrap().1;
     let current_servers: HashSet<usize> = {
         server.0.lock().unwrap().clone()
     };
 
-    // Unload all position updates from channel buffer
-    let mut position_updates = HashMap::new();
+    // Unload all position updates from channel buffer, keeping any for players we haven't spawned yet
     let receiver = receiver.lock().unwrap();
+    let mut sequences = sequences.0.lock().unwrap();
     while let Ok(position_update) = receiver.try_recv() {
         if position_update.player_id == current_player_struct.id {continue;} // Skip updating this player
+        // Drop updates older than one we already have, so players don't jump backwards
+        if !sequences.accept(position_update.player_id, position_update.seq) {continue;}
 
         if let Some(pu) = position_updates.get_mut(&position_update.player_id) {
             *pu = position_update;
@@ -33,7 +40,7 @@ pub fn sync_positions(
 
     let initial_position = Vec3::ONE * 10000.;
     for (player, mut transform, mut interpolate_position) in other_player_query.iter_mut() {
-        if let Some(pu) = position_updates.get(&player.id) {
+        if let Some(pu) = position_updates.remove(&player.id) {
             if transform.translation == initial_position {
                 // First time setting position, don't interpolate
                 transform.translation = pu.position;
@@ -43,79 +50,381 @@ pub fn sync_positions(
     }
 
     // Send position to server
+    *seq = seq.wrapping_add(1);
     let position_update = PositionUpdate {
         player_id: current_player_struct.id,
-        position: current_player_transform.translation
+        position: current_player_transform.translation,
+        seq: *seq,
+        timestamp: timestamp_now()
     };
+    let server_addresses = server_addresses.0.lock().unwrap();
+    let sessions = server_sessions.sessions.lock().unwrap();
+    let decoders = server_sessions.decoders.lock().unwrap();
     for server in current_servers {
-        socket.send_to(&bincode::serialize(&position_update).unwrap(), crate::SERVER_RECEIVING_PORTS[server])
+        let session = match sessions.get(&server) {
+            Some(s) => s,
+            None => continue, // Not registered yet
+        };
+        let message = match session.encoding {
+            Encoding::Bincode => ClientMessage::Update(ClientUpdate { session_key: session.session_key, update: position_update.clone() }),
+            Encoding::Compact => ClientMessage::Compact(CompactUpdate {
+                session_key: session.session_key,
+                position: session.quantizer.quantize(position_update.position),
+                seq: position_update.seq,
+                timestamp: position_update.timestamp,
+                ack: decoders.get(&session.session_key).and_then(|d| d.ack),
+            }),
+        };
+        let address = match server_address(&server_addresses, server) {
+            Some(a) => a,
+            None => continue, // Terminated, we'll be moved off it at the next server sync
+        };
+        socket.send_to(&bincode::serialize(&message).unwrap(), &address.udp)
             .expect("Failed to send position update");
     }
 }
 
+/// Send queued reliable messages and acks to each server, resending anything that hasn't been acked
+pub fn sync_reliable(socket: Res<UdpSocket>,
+    server: Res<crate::Server>,
+    server_addresses: Res<crate::ServerAddresses>,
+    server_sessions: Res<crate::ServerSessions>
+) {
+    let current_servers = {
+        server.0.lock().unwrap().clone()
+    };
+    let mut failed = vec![];
+    {
+        let server_addresses = server_addresses.0.lock().unwrap();
+        let sessions = server_sessions.sessions.lock().unwrap();
+        let mut endpoints = server_sessions.endpoints.lock().unwrap();
+        let now = Instant::now();
+        for server in current_servers {
+            let (session_key, endpoint) = match sessions.get(&server).and_then(|s| Some((s.session_key, endpoints.get_mut(&s.session_key)?))) {
+                Some(e) => e,
+                None => continue, // Not registered yet
+            };
+            let address = match server_address(&server_addresses, server) {
+                Some(a) => a,
+                None => continue,
+            };
+            for message in endpoint.poll(now) {
+                if let Err(e) = socket.send_to(&bincode::serialize(&ClientMessage::Reliable { session_key, message }).unwrap(), &address.udp) {
+                    eprintln!("Failed to send reliable message: {}", e); // Resent once it's due again
+                }
+            }
+            if endpoint.failed() {
+                failed.push(server);
+            }
+        }
+    }
+    // The server stopped acking, forget the session so the next server sync registers again
+    for lost in failed {
+        server_sessions.remove(lost);
+        server.0.lock().unwrap().remove(&lost);
+    }
+}
+
+/// Let the other players know when we jump
+pub fn send_actions(keys: Res<Input<KeyCode>>, current_player_struct: Res<Player>, server_sessions: Res<crate::ServerSessions>) {
+    if !keys.just_pressed(KeyCode::Space) {return;}
+    let payload = bincode::serialize(&GameEvent::Action { player_id: current_player_struct.id, action: "jump".to_string() }).unwrap();
+    for endpoint in server_sessions.endpoints.lock().unwrap().values_mut() {
+        endpoint.send(ACTION_CHANNEL, Delivery::Unordered, payload.clone());
+    }
+}
+
+/// Send what we typed to our server. Lines starting with /r reach everyone on our server and /g everyone in the world, the rest reach players nearby
+pub fn send_chat(chat_input: Res<crate::ChatInput>, current_player_struct: Res<Player>, server: Res<crate::Server>, server_sessions: Res<crate::ServerSessions>) {
+    let chat_input = chat_input.0.lock().unwrap();
+    while let Ok(line) = chat_input.try_recv() {
+        let (scope, text) = match line.split_once(' ') {
+            Some(("/r", text)) => (ChatScope::Region, text),
+            Some(("/g", text)) => (ChatScope::Global, text),
+            _ => (ChatScope::Proximity, line.as_str()),
+        };
+        // Servers would cut it short anyway, and a long line wouldn't fit in a reliable message
+        let text: String = text.chars().take(MAX_CHAT_LENGTH).collect();
+        let payload = bincode::serialize(&GameEvent::Chat { player_id: current_player_struct.id, scope, text }).unwrap();
+        // Only the server that owns us passes chat on, so it is only said once
+        let sessions = server_sessions.sessions.lock().unwrap();
+        let mut endpoints = server_sessions.endpoints.lock().unwrap();
+        for server in server.0.lock().unwrap().iter() {
+            if let Some(endpoint) = sessions.get(server).and_then(|s| endpoints.get_mut(&s.session_key)) {
+                endpoint.send(CHAT_CHANNEL, Delivery::Ordered, payload.clone());
+            }
+        }
+    }
+}
+
+/// Spawn, despawn and update players and entities as servers tell us about them, rather than waiting for the next sync
+#[allow(clippy::too_many_arguments)]
+pub fn handle_events(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    events: Res<crate::GameEvents>,
+    sequences: Res<crate::UpdateSequences>,
+    mut other_player_query: Query<(Entity, &mut Player, &Handle<StandardMaterial>), With<InterpolatePosition>>,
+    mut entity_query: Query<(Entity, &mut ReplicatedEntity)>,
+    current_player_struct: Res<Player>
+) {
+    let events = events.0.lock().unwrap();
+    while let Ok(event) = events.try_recv() {
+        match event {
+            GameEvent::PlayerJoined { player_id } => {
+                if player_id == current_player_struct.id || other_player_query.iter().any(|(_, p, _)| p.id == player_id) {continue;}
+                spawn_other_player(&mut commands, &mut meshes, &mut materials, Player::new(player_id));
+            },
+            GameEvent::PlayerLeft { player_id } => {
+                sequences.0.lock().unwrap().remove(&player_id);
+                for (entity, _, _) in other_player_query.iter().filter(|(_, p, _)| p.id == player_id) {
+                    commands.entity(entity).despawn();
+                }
+            },
+            GameEvent::Action { player_id, action } => info!("Player {} did {}", player_id, action),
+            GameEvent::AttributesChanged { player_id, attributes } => {
+                for (_, mut player, material) in other_player_query.iter_mut().filter(|(_, p, _)| p.id == player_id) {
+                    apply_attributes(&mut player, material, &mut materials, attributes.clone());
+                }
+            },
+            GameEvent::EntitySpawned(entity) => {
+                if entity_query.iter().any(|(_, e)| e.id == entity.id) {continue;}
+                crate::entities::spawn_entity(&mut commands, &mut meshes, &mut materials, entity);
+            },
+            GameEvent::EntityChanged { id, data } => {
+                for (_, mut entity) in entity_query.iter_mut().filter(|(_, e)| e.id == id) {
+                    attributes::merge(&mut entity.data, data.clone());
+                }
+            },
+            GameEvent::EntityDespawned { id } => {
+                for (entity, _) in entity_query.iter().filter(|(_, e)| e.id == id) {
+                    commands.entity(entity).despawn();
+                }
+            },
+            GameEvent::Chat { player_id, scope, text } => {
+                // Players too far away to see, such as global chat from elsewhere, go by their ID
+                let name = other_player_query.iter().find(|(_, p, _)| p.id == player_id)
+                    .and_then(|(_, p, _)| match p.attributes.get(NAME) {
+                        Some(AttributeValue::Text(name)) => Some(name.clone()),
+                        _ => None,
+                    })
+                    .unwrap_or_else(|| player_id.to_string());
+                info!("[{:?}] {}: {}", scope, name, text);
+            }
+        }
+    }
+}
+
+/// Move back to where the server put us when it clamped one of our moves
+pub fn apply_corrections(corrections: Res<crate::Corrections>, mut main_player_query: Query<&mut Transform, (With<Player>, Without<InterpolatePosition>)>) {
+    // Only the latest one matters
+    let correction = match corrections.0.lock().unwrap().try_iter().last() {
+        Some(c) => c,
+        None => return,
+    };
+    for mut transform in main_player_query.iter_mut() {
+        transform.translation = correction.position;
+    }
+}
+
+/// Log how updates from other players have been arriving
+pub fn log_update_stats(sequences: Res<crate::UpdateSequences>) {
+    let stats = sequences.0.lock().unwrap().stats;
+    // Servers only send us updates for players we're interested in, so gaps in sequence numbers don't mean anything was lost
+    info!("Position updates: {} accepted, {} dropped for arriving after a newer one", stats.accepted, stats.stale);
+}
+
 /// Update the current servers we are running on
+#[allow(clippy::too_many_arguments)]
 pub fn sync_servers(server: Res<crate::Server>, 
+    server_addresses: Res<crate::ServerAddresses>,
     current_player_struct: Res<Player>, 
+    player_token: Res<crate::PlayerToken>,
     main_player_query: Query<(&Player, &Transform), Without<InterpolatePosition>>,
-    receive_port: Res<crate::ReceivePort>
+    receive_port: Res<crate::ReceivePort>,
+    coord_socket: Res<crate::CoordSocket>,
+    routing: Res<crate::Routing>,
+    position_sender: Res<crate::PositionSender>,
+    server_sessions: Res<crate::ServerSessions>
 ) {
     let current_player_transform = main_player_query.iter().next().unwrap().1;
+    // Look up the server that owns our position in our copy of the routing table, or ask the coord if we don't keep one
+    let local_servers = routing.0.lock().unwrap().as_ref().map(|table| [table.owner(current_player_transform.translation)].into_iter().collect());
+    let new_servers = match local_servers.or_else(|| query_servers(&coord_socket, current_player_transform.translation)) {
+        Some(servers) => servers,
+        None => return, // Stay where we are until the next sync
+    };
+    switch_servers(new_servers, &server, &server_addresses, &current_player_struct, &player_token, &receive_port, &position_sender, &server_sessions);
+}
+
+/// Bring our copy of the routing table up to date with the coord's
+pub fn sync_routing(routing: Res<crate::Routing>) {
+    let mut routing = routing.0.lock().unwrap();
+    let table = match routing.as_mut() {
+        Some(t) => t,
+        None => return, // Using remote lookups
+    };
+    let updates = reqwest::blocking::get(format!("{}/get_routing_updates?generation={}&since={}", crate::COORD_SERVER_ADDRESS, table.generation, table.epoch))
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.json::<Vec<RoutingUpdate>>());
+    let up_to_date = match updates {
+        Ok(updates) => updates.iter().all(|u| table.apply(u)),
+        Err(_) => false, // Too far behind, or the coord restarted
+    };
+    if !up_to_date {
+        match fetch_routing_table() {
+            Ok(t) => *table = t,
+            Err(e) => eprintln!("Failed to download routing table: {}", e), // Keep using the old one
+        }
+    }
+}
+
+/// Download the coord's whole routing table
+pub fn fetch_routing_table() -> reqwest::Result<RoutingTable> {
+    reqwest::blocking::get(format!("{}/get_routing", crate::COORD_SERVER_ADDRESS))?
+        .error_for_status()?.json()
+}
+
+/// Ask the coordination server which servers a position is on, over its binary query socket
+fn query_servers(coord_socket: &crate::CoordSocket, position: Vec3) -> Option<HashSet<usize>> {
+    let request_id = coord_socket.1.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = coord_socket.0.send(&bincode::serialize(&CoordRequest::GetServer { request_id, position }).unwrap()) {
+        eprintln!("Failed to send server query: {}", e);
+        return None;
+    }
+    let mut buf = [0; 512];
+    loop {
+        let amt = match coord_socket.0.recv(&mut buf) {
+            Ok(amt) => amt,
+            Err(e) => {
+                eprintln!("No reply to server query: {}", e);
+                return None;
+            }
+        };
+        match bincode::deserialize(&buf[..amt]) {
+            Ok(CoordResponse::Servers { request_id: id, servers }) if id == request_id => return Some(servers),
+            _ => continue, // Reply to an older query that timed out
+        }
+    }
+}
+
+/// Move straight to the servers we were told to by a handoff, rather than waiting for the next server sync
+#[allow(clippy::too_many_arguments)]
+pub fn handle_handoffs(handoff_notice: Res<crate::HandoffNotice>,
+    server: Res<crate::Server>,
+    server_addresses: Res<crate::ServerAddresses>,
+    current_player_struct: Res<Player>,
+    player_token: Res<crate::PlayerToken>,
+    receive_port: Res<crate::ReceivePort>,
+    position_sender: Res<crate::PositionSender>,
+    server_sessions: Res<crate::ServerSessions>
+) {
+    let new_servers = handoff_notice.0.lock().unwrap().take();
+    if let Some(new_servers) = new_servers {
+        switch_servers(new_servers, &server, &server_addresses, &current_player_struct, &player_token, &receive_port, &position_sender, &server_sessions);
+    }
+}
+
+/// Leave servers we aren't on anymore and join new ones
+#[allow(clippy::too_many_arguments)]
+fn switch_servers(new_servers: HashSet<usize>,
+    server: &crate::Server,
+    server_addresses: &crate::ServerAddresses,
+    current_player_struct: &Player,
+    player_token: &crate::PlayerToken,
+    receive_port: &crate::ReceivePort,
+    position_sender: &crate::PositionSender,
+    server_sessions: &crate::ServerSessions
+) {
     let last_servers = {
         server.0.lock().unwrap().clone()
     };
-    let new_servers = reqwest::blocking::Client::new().post(format!("{}/get_server", crate::COORD_SERVER_ADDRESS)).header("Content-Type", "application/json")
-        .body(serde_json::to_string(&current_player_transform.translation).unwrap())
-        .send().unwrap().json::<HashSet<usize>>().unwrap();
     let switched_server = last_servers != new_servers;
     // Switch server if nessacary
     if switched_server {
-        // Send leave request to servers we are leaving
+        let mut server_addresses = server_addresses.0.lock().unwrap();
+        // Refresh server addresses if the coord handed us a server we haven't heard of
+        if new_servers.iter().any(|s| server_addresses.get(*s).and_then(|a| a.as_ref()).is_none()) {
+            *server_addresses = fetch_server_addresses();
+        }
+        // Have servers we are leaving pass what they know about us on to the server we are moving to,
+        // before we register with it, and just leave if they can't
+        let destination = if new_servers.len() == 1 {new_servers.iter().next().copied()} else {None};
         for server in last_servers.difference(&new_servers) {
-            reqwest::blocking::Client::new().post(format!("{}/unregister_player", crate::SERVER_ADDRESSES[*server])).header("Content-Type", "application/json")
-                .body(serde_json::to_string(&current_player_struct.id).unwrap())
-                .send().unwrap();
+            // Servers that have been terminated have nothing to pass on and nobody to tell
+            if let Some(address) = server_address(&server_addresses, *server) {
+                let transferred = destination.is_some_and(|to| transfer_to_server(current_player_struct.id, &player_token.0, address, to));
+                if !transferred {
+                    send_exit_to_server(current_player_struct.id, &player_token.0, address);
+                }
+            }
+            server_sessions.remove(*server);
         }
         // Send join request to new servers we are joining
+        let mut joined = new_servers.clone();
         for server in new_servers.difference(&last_servers) {
-            reqwest::blocking::Client::new().post(format!("{}/register_player", crate::SERVER_ADDRESSES[*server])).header("Content-Type", "application/json")
+            let address = match server_address(&server_addresses, *server) {
+                Some(a) => a,
+                None => {
+                    joined.remove(server); // Try again next sync
+                    continue;
+                }
+            };
+            let registered: PlayerRegistered = reqwest::blocking::Client::new().post(format!("{}/register_player", address.http)).header("Content-Type", "application/json")
+                .header(AUTH_HEADER, &player_token.0)
                 .body(serde_json::to_string(
                     &PlayerRegister {
                         player: current_player_struct.clone(),
-                        address: format!("127.0.0.1:{}", receive_port.0)
+                        address: format!("127.0.0.1:{}", receive_port.0),
+                        encodings: server_sessions.encodings.clone()
                     }
                 ).unwrap())
-                .send().unwrap();
+                .send().unwrap()
+                .json().unwrap();
+            server_sessions.insert(*server, &registered);
+            // Place players on the new server straight away
+            let position_sender = position_sender.0.lock().unwrap();
+            for update in registered.snapshot {
+                position_sender.send(update).expect("Failed to put update in queue");
+            }
         }
         // Switch server resource
-        *server.0.lock().unwrap() = new_servers;
+        *server.0.lock().unwrap() = joined;
     }
 }
 
 /// Sync players from server
+#[allow(clippy::too_many_arguments)]
 pub fn sync_players(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, 
     mut materials: ResMut<Assets<StandardMaterial>>, 
     server: Res<crate::Server>,
-    mut other_player_query: Query<(Entity, &Player), With<InterpolatePosition>>,
+    server_addresses: Res<crate::ServerAddresses>,
+    mut other_player_query: Query<(Entity, &mut Player, &Handle<StandardMaterial>), With<InterpolatePosition>>,
+    sequences: Res<crate::UpdateSequences>,
     current_player_struct: Res<Player>) {
     let server_nums = {
         server.0.lock().unwrap().clone()
     };
+    let server_addresses = server_addresses.0.lock().unwrap().clone();
 
     // Get players
-    let mut players: HashMap<Uuid, bool> = HashMap::new();
+    let mut players: HashMap<Uuid, Player> = HashMap::new();
     for server in server_nums {
-        players.extend(reqwest::blocking::get(format!("{}/get_players", crate::SERVER_ADDRESSES[server]))
-            .unwrap().json::<HashMap<Uuid, Player>>().unwrap() // Parse original hashmap
-            .into_iter().map(|(k, _)| (k, k == current_player_struct.id)).collect::<HashMap<Uuid, bool>>()); // Replace values with false
+        if let Some(address) = server_address(&server_addresses, server) {
+            players.extend(reqwest::blocking::get(format!("{}/get_players", address.http))
+                .unwrap().json::<HashMap<Uuid, Player>>().unwrap());
+        }
     }
+    players.remove(&current_player_struct.id); // Skip this player
 
     let mut entities_to_kill = vec![];
-    for (entity, player) in other_player_query.iter_mut() {
-        if let Some(p) = players.get_mut(&player.id) {
-            *p = true;
+    for (entity, mut player, material) in other_player_query.iter_mut() {
+        if let Some(p) = players.remove(&player.id) {
+            // Already spawned, catch up on any attribute changes we missed
+            apply_attributes(&mut player, material, &mut materials, p.attributes);
         } else {
             // Player quit, remove
             entities_to_kill.push(entity);
+            sequences.0.lock().unwrap().remove(&player.id);
         }
     }
     for entity in entities_to_kill {
@@ -123,34 +432,132 @@ pub fn sync_players(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>,
     }
 
     // Spawn players we haven't seen
-    for (i, _) in players.iter().filter(|(_, s)| !**s) {
-        commands.spawn_bundle(PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
-            material: materials.add(Color::rgb(1.0, 0.2, 0.2).into()),
-            transform: Transform::from_translation(Vec3::ONE * 10000.),
-            ..Default::default()
-        }).insert(Player{id:*i})
-        .insert(InterpolatePosition{target: Vec3::ONE * 10000.});
+    for (_, player) in players {
+        spawn_other_player(&mut commands, &mut meshes, &mut materials, player);
+    }
+}
+
+/// Spawn another player out of sight, they are moved into place by their first position update
+fn spawn_other_player(commands: &mut Commands, meshes: &mut Assets<Mesh>, materials: &mut Assets<StandardMaterial>, player: Player) {
+    let color = match player.attributes.get(COLOR) {
+        Some(AttributeValue::Color([r, g, b])) => Color::rgb(*r, *g, *b),
+        _ => Color::rgb(1.0, 0.2, 0.2),
+    };
+    commands.spawn_bundle(PbrBundle {
+        mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
+        material: materials.add(color.into()),
+        transform: Transform::from_translation(Vec3::ONE * 10000.),
+        ..Default::default()
+    }).insert(player)
+    .insert(InterpolatePosition{target: Vec3::ONE * 10000.});
+}
+
+/// Bring another player's attributes up to date, redrawing them if their colour changed
+fn apply_attributes(player: &mut Player, material: &Handle<StandardMaterial>, materials: &mut Assets<StandardMaterial>, attributes: Attributes) {
+    let changed = player.set_attributes(attributes);
+    if let (Some(AttributeValue::Color([r, g, b])), Some(material)) = (changed.get(COLOR), materials.get_mut(material)) {
+        material.base_color = Color::rgb(*r, *g, *b);
+    }
+    if let Some(AttributeValue::Text(name)) = changed.get(NAME) {
+        info!("Player {} is now called {}", player.id, name);
     }
 }
 
 // Capture any position changes sent from server and put in queue
-pub fn capture_changes(sender: Sender<PositionUpdate>, socket: UdpSocket) {
+#[allow(clippy::too_many_arguments)]
+pub fn capture_changes(sender: Sender<PositionUpdate>,
+    handoff_notice: Arc<Mutex<Option<HashSet<usize>>>>,
+    decoders: Arc<Mutex<HashMap<u64, CompactDecoder>>>,
+    endpoints: Arc<Mutex<HashMap<u64, ReliableEndpoint>>>,
+    events: Sender<GameEvent>,
+    entity_updates: Sender<EntityUpdate>,
+    corrections: Sender<PositionUpdate>,
+    socket: UdpSocket
+) {
     loop {
-        // Wait for a position update from server
+        // Wait for a message from server
         let mut buf = [0; 2048];
         let (amt, _) = socket.recv_from(&mut buf)
             .expect("Failed to receive");
-        let update: PositionUpdate = bincode::deserialize(&buf[..amt]).unwrap();
-
-        // Put update in channel queue
-        sender.send(update)
-            .expect("Failed to put update in queue");
+        let message = match bincode::deserialize(&buf[..amt]) {
+            Ok(m) => m,
+            Err(_) => continue, // Not something we understand, drop it
+        };
+        match message {
+            ServerMessage::PositionUpdate(update) => {
+                // Put update in channel queue
+                sender.send(update)
+                    .expect("Failed to put update in queue");
+            },
+            ServerMessage::Snapshot(updates) => {
+                for update in updates {
+                    sender.send(update)
+                        .expect("Failed to put update in queue");
+                }
+            },
+            ServerMessage::Compact(snapshot) => {
+                // Rebuild the snapshot against the one we last acknowledged to that server
+                let updates = match decoders.lock().unwrap().get_mut(&snapshot.session_key) {
+                    Some(decoder) => decoder.decode(&snapshot),
+                    None => continue, // From a server we've left
+                };
+                for update in updates {
+                    sender.send(update)
+                        .expect("Failed to put update in queue");
+                }
+            },
+            ServerMessage::Handoff { servers } => {
+                *handoff_notice.lock().unwrap() = Some(servers);
+            },
+            ServerMessage::Reliable { session_key, message } => {
+                // Acks for these go out with the next reliable sync
+                let payloads = match endpoints.lock().unwrap().get_mut(&session_key) {
+                    Some(endpoint) => endpoint.receive(message),
+                    None => continue, // From a server we've left
+                };
+                for event in payloads.iter().filter_map(|p| bincode::deserialize(p).ok()) {
+                    events.send(event)
+                        .expect("Failed to put event in queue");
+                }
+            },
+            ServerMessage::EntityUpdates(updates) => {
+                for update in updates {
+                    entity_updates.send(update)
+                        .expect("Failed to put entity update in queue");
+                }
+            },
+            ServerMessage::Correction(update) => {
+                corrections.send(update)
+                    .expect("Failed to put correction in queue");
+            }
+        }
     }
 }
 
-pub fn send_exit_to_server(player_id: Uuid, server_num: usize) {
-    reqwest::blocking::Client::new().post(format!("{}/unregister_player", crate::SERVER_ADDRESSES[server_num])).header("Content-Type", "application/json")
+pub fn send_exit_to_server(player_id: Uuid, player_token: &str, server_address: &ServerAddress) {
+    // It may have gone already, in which case there is nobody to tell
+    let _ = reqwest::blocking::Client::new().post(format!("{}/unregister_player", server_address.http)).header("Content-Type", "application/json")
+        .header(AUTH_HEADER, player_token)
         .body(serde_json::to_string(&player_id).unwrap())
-        .send().unwrap();
-}
\ No newline at end of file
+        .send();
+}
+
+/// Ask a server we are leaving to hand our state over to another server, returns false if it couldn't
+pub fn transfer_to_server(player_id: Uuid, player_token: &str, server_address: &ServerAddress, to: usize) -> bool {
+    reqwest::blocking::Client::new().post(format!("{}/transfer_player", server_address.http)).header("Content-Type", "application/json")
+        .header(AUTH_HEADER, player_token)
+        .body(serde_json::to_string(&PlayerTransfer { player_id, to }).unwrap())
+        .send().and_then(|r| r.error_for_status())
+        .is_ok()
+}
+
+/// Get the addresses of every server from the coordination server
+pub fn fetch_server_addresses() -> Vec<Option<ServerAddress>> {
+    reqwest::blocking::get(format!("{}/get_servers", crate::COORD_SERVER_ADDRESS))
+        .unwrap().json().unwrap()
+}
+
+/// Look up the address of a server, None if it has been terminated
+pub fn server_address(server_addresses: &[Option<ServerAddress>], server_num: usize) -> Option<&ServerAddress> {
+    server_addresses.get(server_num)?.as_ref()
+}
@@ -0,0 +1,53 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use game_structs::{auth::ServiceAuth, operations::{ChatMessages, GlobalChat}};
+use rocket::{get, post, State, serde::json::Json};
+use uuid::Uuid;
+
+static CHAT_RING_SIZE: usize = 1000; // How many global chat messages to keep for servers to pick up
+
+pub type Chat = Arc<Mutex<ChatLog>>;
+
+/// Recent global chat, servers post what their players say and poll for what everyone else's said
+pub struct ChatLog {
+    generation: u64, // Random for each run, so servers can tell IDs have started again
+    messages: VecDeque<GlobalChat>,
+    next_id: u64,
+}
+
+impl Default for ChatLog {
+    fn default() -> Self {
+        Self { generation: Uuid::new_v4().as_u128() as u64, messages: VecDeque::new(), next_id: 0 }
+    }
+}
+
+impl ChatLog {
+    pub fn record(&mut self, mut message: GlobalChat) -> u64 {
+        message.id = self.next_id;
+        self.next_id += 1;
+        if self.messages.len() == CHAT_RING_SIZE {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+        self.next_id - 1
+    }
+
+    /// Every message still in memory with an ID of at least `since`, or all of them if `since` is from another generation
+    pub fn since(&self, generation: Option<u64>, since: u64) -> ChatMessages {
+        let since = if generation == Some(self.generation) {since} else {0};
+        ChatMessages {
+            generation: self.generation,
+            messages: self.messages.iter().filter(|m| m.id >= since).cloned().collect(),
+        }
+    }
+}
+
+#[post("/send_chat", format = "json", data = "<message>")]
+pub fn send_chat(chat: &State<Chat>, message: Json<GlobalChat>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&chat.lock().unwrap().record(message.into_inner())).unwrap()
+}
+
+#[get("/get_chat?<generation>&<since>")]
+pub fn get_chat(chat: &State<Chat>, generation: Option<u64>, since: Option<u64>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&chat.lock().unwrap().since(generation, since.unwrap_or(0))).unwrap()
+}
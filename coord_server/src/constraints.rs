@@ -0,0 +1,141 @@
+use std::sync::{Arc, RwLock};
+
+use game_structs::{Aabb, auth::ServiceAuth};
+use rocket::{get, post, State, serde::json::Json};
+use serde::{Serialize, Deserialize};
+
+static MIN_PIN_REGION_SIZE: f32 = 16.; // Stop splitting to fit a pin once regions are this small
+
+pub type SharedConstraints = Arc<RwLock<Constraints>>;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum PinStatus {
+    /// Waiting for the next restructuring pass to reserve the server
+    Pending,
+    Active,
+    Rejected(String),
+}
+
+/// A region of the world that must be served by one dedicated server
+#[derive(Serialize, Clone, Debug)]
+pub struct Pin {
+    pub id: u64,
+    pub region: Aabb,
+    pub index: usize,
+    pub status: PinStatus,
+}
+
+/// A region of the world whose servers must never be merged
+#[derive(Serialize, Clone, Debug)]
+pub struct Lock {
+    pub id: u64,
+    pub region: Aabb,
+}
+
+/// How a block of the tree is affected by pins
+pub enum PinCoverage {
+    None,
+    /// A pin covers part of the block, so it needs splitting
+    Partial,
+    /// The block belongs to the server with this index
+    Pinned(usize),
+}
+
+/// Manual overrides admins can place on restructuring
+#[derive(Serialize, Default, Debug)]
+pub struct Constraints {
+    pub pins: Vec<Pin>,
+    pub locks: Vec<Lock>,
+    next_id: u64,
+}
+
+impl Constraints {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Reserve the servers of pending pins, rejecting pins whose server isn't free
+    pub fn apply_pending(&mut self, free_servers: &mut [bool]) {
+        for pin in self.pins.iter_mut().filter(|p| p.status == PinStatus::Pending) {
+            if free_servers.get(pin.index).copied().unwrap_or(false) {
+                free_servers[pin.index] = false;
+                pin.status = PinStatus::Active;
+            } else {
+                pin.status = PinStatus::Rejected("Server is not free".to_string());
+            }
+        }
+    }
+
+    /// Indices of servers reserved by active pins
+    pub fn pinned_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pins.iter().filter(|p| p.status == PinStatus::Active).map(|p| p.index)
+    }
+
+    pub fn pin_coverage(&self, region: Aabb) -> PinCoverage {
+        let mut coverage = PinCoverage::None;
+        for pin in self.pins.iter().filter(|p| p.status == PinStatus::Active && p.region.intersects(&region)) {
+            if pin.region.contains_aabb(&region) || (region.size().max_element() <= MIN_PIN_REGION_SIZE && pin.region.contains(region.center())) {
+                return PinCoverage::Pinned(pin.index);
+            }
+            if region.size().max_element() > MIN_PIN_REGION_SIZE {
+                coverage = PinCoverage::Partial;
+            }
+        }
+        coverage
+    }
+
+    /// Whether servers in this region must not be merged
+    pub fn is_locked(&self, region: Aabb) -> bool {
+        self.locks.iter().any(|l| l.region.intersects(&region))
+            || self.pins.iter().any(|p| p.status == PinStatus::Active && p.region.intersects(&region))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PinRequest {
+    pub region: Aabb,
+    pub index: usize,
+}
+
+/// Pin a region to a free server, returns the ID of the pin
+#[post("/pin_region", format = "json", data = "<pin>")]
+pub fn pin_region(constraints: &State<SharedConstraints>, pin: Json<PinRequest>, _auth: ServiceAuth) -> String {
+    let mut constraints = constraints.write().unwrap();
+    let id = constraints.next_id();
+    constraints.pins.push(Pin {
+        id,
+        region: pin.region,
+        index: pin.index,
+        status: PinStatus::Pending,
+    });
+    serde_json::to_string(&id).unwrap()
+}
+
+/// Remove a pin, its server keeps its region but can be merged again
+#[post("/unpin_region", format = "json", data = "<id>")]
+pub fn unpin_region(constraints: &State<SharedConstraints>, id: Json<u64>, _auth: ServiceAuth) {
+    constraints.write().unwrap().pins.retain(|p| p.id != *id);
+}
+
+/// Stop servers in a region being merged, returns the ID of the lock
+#[post("/lock_region", format = "json", data = "<region>")]
+pub fn lock_region(constraints: &State<SharedConstraints>, region: Json<Aabb>, _auth: ServiceAuth) -> String {
+    let mut constraints = constraints.write().unwrap();
+    let id = constraints.next_id();
+    constraints.locks.push(Lock {
+        id,
+        region: *region,
+    });
+    serde_json::to_string(&id).unwrap()
+}
+
+#[post("/unlock_region", format = "json", data = "<id>")]
+pub fn unlock_region(constraints: &State<SharedConstraints>, id: Json<u64>, _auth: ServiceAuth) {
+    constraints.write().unwrap().locks.retain(|l| l.id != *id);
+}
+
+#[get("/get_constraints")]
+pub fn get_constraints(constraints: &State<SharedConstraints>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&*constraints.read().unwrap()).unwrap()
+}
@@ -0,0 +1,84 @@
+use std::{collections::VecDeque, fs::{File, OpenOptions}, io::{self, Write}, path::Path, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use game_structs::{Aabb, Vec3, auth::ServiceAuth};
+use rocket::{get, State};
+use serde::Serialize;
+
+static EVENT_RING_SIZE: usize = 1000; // How many events to keep in memory
+
+pub type Events = Arc<Mutex<EventLog>>;
+
+/// A decision made while restructuring servers
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A region was split into an octree at the split point, children lists the server for each octant.
+    /// Predicted is the expected population of each octant, empty if the server didn't report where its players are
+    Split { region: Aabb, index: usize, population: usize, split: Vec3, children: Vec<usize>, predicted: Vec<usize> },
+    /// Two neighbouring regions were merged onto one server
+    Merge { region: Aabb, index: usize, merged_index: usize, population: usize },
+    /// A free server was given a region
+    Allocate { region: Aabb, index: usize },
+    /// A server was returned to the free pool
+    Free { index: usize },
+    /// A region needed splitting but there were no free servers
+    FailedAllocation { region: Aabb, index: usize, population: usize },
+    /// A region had too many players but they were too close together for a split to help
+    SkippedSplit { region: Aabb, index: usize, population: usize, predicted: Vec<usize> },
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RestructureEvent {
+    pub id: u64,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Keeps recent events in memory and appends every event to a JSON lines file
+pub struct EventLog {
+    events: VecDeque<RestructureEvent>,
+    next_id: u64,
+    file: Option<File>,
+}
+
+impl EventLog {
+    pub fn new(path: Option<&Path>) -> io::Result<Self> {
+        Ok(Self {
+            events: VecDeque::with_capacity(EVENT_RING_SIZE),
+            next_id: 0,
+            file: match path {
+                Some(p) => Some(OpenOptions::new().create(true).append(true).open(p)?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn record(&mut self, kind: EventKind) {
+        let event = RestructureEvent {
+            id: self.next_id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            kind,
+        };
+        self.next_id += 1;
+
+        if let Some(file) = self.file.as_mut() {
+            let _ = writeln!(file, "{}", serde_json::to_string(&event).unwrap());
+        }
+        if self.events.len() == EVENT_RING_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Every event still in memory with an ID of at least `since`
+    pub fn since(&self, since: u64) -> Vec<RestructureEvent> {
+        self.events.iter().filter(|e| e.id >= since).cloned().collect()
+    }
+}
+
+#[get("/get_events?<since>")]
+pub fn get_events(events: &State<Events>, since: Option<u64>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&events.lock().unwrap().since(since.unwrap_or(0))).unwrap()
+}
@@ -0,0 +1,142 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use game_structs::{
+    ServerAddress, Vec3,
+    auth::{AuthSecret, ServiceAuth, AUTH_HEADER},
+    operations::{HandoffComplete, HandoffRequest},
+    routing::RoutingTable
+};
+use rocket::{get, post, serde::json::Json, State};
+use serde::Serialize;
+use uuid::Uuid;
+
+static HANDOFF_DEADLINE_MILLIS: u64 = 5000; // How long players get to move before their old server drops them
+static FINISHED_HANDOFFS_KEPT: usize = 100; // How many finished handoffs to keep around for inspection
+
+pub type SharedHandoffs = Arc<Mutex<Handoffs>>;
+
+/// Progress of moving players after one restructuring
+#[derive(Serialize, Clone, Debug)]
+pub struct HandoffState {
+    pub id: u64,
+    /// Milliseconds since the unix epoch
+    pub started: u128,
+    pub deadline: u128,
+    /// Players that haven't left yet, and the server they are leaving
+    pub pending: HashMap<Uuid, usize>,
+    pub completed: usize,
+    pub timed_out: usize,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Handoffs {
+    active: HashMap<u64, HandoffState>,
+    finished: VecDeque<HandoffState>,
+    next_id: u64,
+}
+
+impl Handoffs {
+    fn complete(&mut self, complete: &HandoffComplete) {
+        let state = match self.active.get_mut(&complete.handoff_id) {
+            Some(s) => s,
+            None => return,
+        };
+        if state.pending.remove(&complete.player_id).is_some() {
+            if complete.timed_out {
+                state.timed_out += 1;
+            } else {
+                state.completed += 1;
+            }
+        }
+        if state.pending.is_empty() {
+            let state = self.active.remove(&complete.handoff_id).unwrap();
+            if self.finished.len() == FINISHED_HANDOFFS_KEPT {
+                self.finished.pop_front();
+            }
+            self.finished.push_back(state);
+        }
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// Tell the servers that lost regions which of their players now belong elsewhere
+pub fn orchestrate_handoff(routing: &RoutingTable, affected: &HashSet<usize>, addresses: &[Option<ServerAddress>], secret: &AuthSecret, handoffs: &SharedHandoffs) {
+    let id = {
+        let mut handoffs = handoffs.lock().unwrap();
+        handoffs.next_id += 1;
+        handoffs.next_id
+    };
+    let started = now_millis();
+    let mut pending = HashMap::new();
+
+    for index in affected {
+        let address = match addresses.get(*index).and_then(|a| a.as_ref()) {
+            Some(a) => a,
+            None => continue,
+        };
+        let positions: HashMap<Uuid, Vec3> = match reqwest::blocking::Client::new().get(format!("{}/get_positions", address.http))
+            .header(AUTH_HEADER, &secret.0)
+            .send().and_then(|r| r.error_for_status()).and_then(|r| r.json()) {
+            Ok(p) => p,
+            Err(_) => continue, // Server is gone, its players will find their new server on their own
+        };
+
+        // Find players who aren't on this server anymore
+        let players: HashMap<Uuid, HashSet<usize>> = positions.into_iter()
+            .map(|(player_id, position)| (player_id, routing.owner(position)))
+            .filter(|(_, owner)| owner != index)
+            .map(|(player_id, owner)| (player_id, [owner].into_iter().collect()))
+            .collect();
+        if players.is_empty() {continue;}
+
+        let request = HandoffRequest {
+            handoff_id: id,
+            deadline_millis: HANDOFF_DEADLINE_MILLIS,
+            players,
+        };
+        let sent = reqwest::blocking::Client::new().post(format!("{}/handoff", address.http)).header("Content-Type", "application/json")
+            .header(AUTH_HEADER, &secret.0)
+            .body(serde_json::to_string(&request).unwrap())
+            .send().and_then(|r| r.error_for_status());
+        if sent.is_ok() {
+            pending.extend(request.players.into_keys().map(|p| (p, *index)));
+        }
+    }
+
+    if !pending.is_empty() {
+        handoffs.lock().unwrap().active.insert(id, HandoffState {
+            id,
+            started,
+            deadline: started + HANDOFF_DEADLINE_MILLIS as u128,
+            pending,
+            completed: 0,
+            timed_out: 0,
+        });
+    }
+}
+
+/// Give up on handoffs whose servers never reported back
+pub fn expire_handoffs(handoffs: &SharedHandoffs) {
+    let mut handoffs = handoffs.lock().unwrap();
+    let now = now_millis();
+    let expired: Vec<HandoffComplete> = handoffs.active.values()
+        .filter(|h| now > h.deadline + HANDOFF_DEADLINE_MILLIS as u128) // Give servers a grace period to report
+        .flat_map(|h| h.pending.keys().map(|p| HandoffComplete { handoff_id: h.id, player_id: *p, timed_out: true }).collect::<Vec<_>>())
+        .collect();
+    for complete in expired {
+        handoffs.complete(&complete);
+    }
+}
+
+#[post("/handoff_complete", format = "json", data = "<complete>")]
+pub fn handoff_complete(handoffs: &State<SharedHandoffs>, complete: Json<HandoffComplete>, _auth: ServiceAuth) {
+    handoffs.lock().unwrap().complete(&complete);
+}
+
+#[get("/get_handoffs")]
+pub fn get_handoffs(handoffs: &State<SharedHandoffs>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&*handoffs.lock().unwrap()).unwrap()
+}
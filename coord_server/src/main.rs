@@ -1,13 +1,32 @@
-use std::{sync::{RwLock, Arc}, thread, collections::HashSet};
+mod chat;
+mod constraints;
+mod events;
+mod handoff;
+mod metrics;
+mod provisioner;
+mod query;
+mod routing;
+mod tree;
 
-use game_structs::Vec3;
+use std::{sync::{RwLock, Arc, Mutex, atomic::Ordering}, thread, time::Instant, path::PathBuf, collections::{HashMap, HashSet}, net::UdpSocket};
+
+use game_structs::{Vec3, Aabb, ServerAddress, auth::{AuthSecret, AUTH_HEADER}, density::DensityHistogram};
 use clap::Parser;
-use rocket::{routes, post, State, serde::json::Json};
+use rocket::{routes, get, post, State, serde::json::Json};
+use chat::{Chat, send_chat, get_chat};
+use constraints::*;
+use events::{EventLog, EventKind, Events, get_events};
+use handoff::*;
+use metrics::{Metrics, get_metrics};
+use provisioner::{Provisioner, LocalProvisioner, PoolConfig, balance_pool};
+use routing::{Routing, SharedRouting, get_routing, get_routing_updates};
+use tree::Server;
 
 static WORLD_SIZE: f32 = 1024.; // The size of the total world
 static MAX_PLAYERS: usize = 100; // The max players we want on a server
 static BORDER_BUFFER_SIZE: f32 = 0.1; // The size of the buffer between which a player will be on both servers as a percentage of total size
-static SERVER_ADDRESSES: [&str; 2] = ["http://127.0.0.1:8000", "http://127.0.0.1:8001"];
+static MAX_SPLIT_SHARE: f32 = 0.9; // Don't split if one octant would be left with more than this share of the players
+static SERVER_ADDRESSES: [(&str, &str); 2] = [("http://127.0.0.1:8000", "127.0.0.1:41794"), ("http://127.0.0.1:8001", "127.0.0.1:47810")]; // Servers started by hand, as (http, udp)
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
@@ -15,42 +34,144 @@ async fn main() -> Result<(), rocket::Error> {
 
     let session = Arc::new(RwLock::new(Server::Num(0, 0))); // Start at one server for entire world
     let session1 = session.clone();
+    let routing: SharedRouting = Arc::new(RwLock::new(Routing::new(session.read().unwrap().to_region_node())));
+    let routing1 = routing.clone();
+    let servers: ServerList = Arc::new(RwLock::new(SERVER_ADDRESSES.iter()
+        .map(|(http, udp)| Some(ServerAddress {http: http.to_string(), udp: udp.to_string()}))
+        .collect()));
+    let servers1 = servers.clone();
+    let metrics = Arc::new(Metrics::default());
+    let metrics1 = metrics.clone();
+    let events: Events = Arc::new(Mutex::new(EventLog::new(args.event_log.as_deref()).expect("Failed to open event log")));
+    let events1 = events.clone();
+    let constraints = SharedConstraints::default();
+    let constraints1 = constraints.clone();
+    let handoffs = SharedHandoffs::default();
+    let handoffs1 = handoffs.clone();
+    let chat = Chat::default();
+
+    // Set up automatic server spawning if we know where the server binary is
+    let secret = AuthSecret(args.secret.clone());
+    let secret1 = secret.clone();
+    let provisioner = args.server_binary.clone().map(|binary| Box::new(LocalProvisioner::new(binary, secret.clone(), format!("http://127.0.0.1:{}", args.port))) as Box<dyn Provisioner>);
+    let pool_config = PoolConfig {
+        min_size: args.min_pool,
+        max_size: args.max_pool,
+        min_free: args.min_free,
+    };
 
     // Launch restructuring thread
     let restructuring_handle = thread::spawn(move || {
-        restructure_servers(session1);
+        restructure_servers(session1, routing1, servers1, metrics1, events1, constraints1, handoffs1, secret1, provisioner, pool_config);
+    });
+
+    // Launch binary query thread
+    let query_socket = UdpSocket::bind(format!("127.0.0.1:{}", args.query_port)).expect("Failed to bind query socket");
+    let routing2 = routing.clone();
+    let metrics2 = metrics.clone();
+    let query_handle = thread::spawn(move || {
+        query::serve_queries(query_socket, routing2, metrics2);
     });
 
     let figment = rocket::Config::figment()
         .merge(("port", args.port));
 
     rocket::custom(figment)
-        .mount("/", routes![get_server])
+        .mount("/", routes![get_server, get_servers, get_metrics, get_events, pin_region, unpin_region, lock_region, unlock_region, get_constraints, handoff_complete, get_handoffs, get_routing, get_routing_updates, send_chat, get_chat])
         .manage(session)
+        .manage(routing)
+        .manage(servers)
+        .manage(metrics)
+        .manage(events)
+        .manage(constraints)
+        .manage(handoffs)
+        .manage(chat)
+        .manage(secret)
         .launch().await?;
 
     restructuring_handle.join().expect("Failed to join restructuring thread.");
+    query_handle.join().expect("Failed to join query thread.");
 
     Ok(())
 }
 
 /// Every 10 seconds redistribute servers based on current player count
-pub fn restructure_servers(session: Session) {
+#[allow(clippy::too_many_arguments)]
+pub fn restructure_servers(session: Session, routing: SharedRouting, servers: ServerList, metrics: Arc<Metrics>, events: Events, constraints: SharedConstraints, handoffs: SharedHandoffs, secret: AuthSecret, mut provisioner: Option<Box<dyn Provisioner>>, pool_config: PoolConfig) {
     // A list of free servers
-    let mut free_servers = vec![false, true]; // First server starts out as used, every other one is free
+    let mut free_servers: Vec<bool> = (0..SERVER_ADDRESSES.len()).map(|i| i != 0).collect(); // First server starts out as used, every other one is free
     loop {
+        // Make sure there are enough free servers before deciding whether to split
+        if let Some(provisioner) = provisioner.as_mut() {
+            let mut addresses = servers.read().unwrap().clone();
+            balance_pool(provisioner.as_mut(), &pool_config, &secret, &mut free_servers, &mut addresses);
+            *servers.write().unwrap() = addresses;
+        }
+
+        let mut affected = HashSet::new();
+        let mut decided = vec![]; // Events are only logged once the servers have answered, so /get_events isn't held up
         {
-            // Get population numbers from servers
+            let start = Instant::now();
+            let world = world_region();
+
+            // Ask the servers how busy they are before taking any locks, so requests aren't held up while they answer
+            let addresses = servers.read().unwrap().clone();
+            let mut leaves = vec![];
+            session.read().unwrap().leaves(world, &mut leaves);
+            let populations = fetch_populations(&leaves, &addresses, &secret);
+            let densities = fetch_densities(&leaves, &populations, &addresses, &secret);
+
             let mut server = session.write().unwrap();
-            
+            let mut constraints = constraints.write().unwrap();
+
             // Update server populations
-            server.update_population();
+            server.update_population(&populations);
+
+            // Reserve servers for new pins
+            constraints.apply_pending(&mut free_servers);
+
+            let mut old_leaves = vec![];
+            server.leaves(world, &mut old_leaves);
 
             // Run restructuring to free up servers
-            server.restructure_free(&mut free_servers);
+            server.restructure_free(world, &constraints, &mut decided);
 
             // Run restructuring to allocate servers if nessacary
-            server.restructure_allocate(&mut free_servers);
+            server.restructure_allocate(world, &mut free_servers, &constraints, &densities, &mut decided);
+
+            // Servers that lost any part of their region need to hand off players
+            let mut new_leaves = vec![];
+            server.leaves(world, &mut new_leaves);
+            affected.extend(old_leaves.iter().filter(|l| !new_leaves.contains(l)).map(|(_, i)| *i));
+
+            // Return servers that merges and pins left without a region to the pool
+            let mut used = HashSet::new();
+            server.indices(&mut used);
+            used.extend(constraints.pinned_indices());
+            for (index, free) in free_servers.iter_mut().enumerate() {
+                if !*free && !used.contains(&index) && addresses[index].is_some() {
+                    *free = true;
+                    decided.push(EventKind::Free { index });
+                }
+            }
+
+            // Let clients know about the new tree
+            routing.write().unwrap().publish(server.to_region_node());
+
+            metrics.record_restructure(start.elapsed());
+            metrics.record_pool(&free_servers);
+        }
+        {
+            let mut events = events.lock().unwrap();
+            for kind in decided {
+                events.record(kind);
+            }
+        }
+
+        // Move players off servers whose regions changed
+        expire_handoffs(&handoffs);
+        if !affected.is_empty() {
+            orchestrate_handoff(&routing.read().unwrap().table, &affected, &servers.read().unwrap(), &secret, &handoffs);
         }
 
         // Sleep for 10 seconds
@@ -59,215 +180,118 @@ pub fn restructure_servers(session: Session) {
 }
 
 #[post("/get_server", format = "json", data = "<position>")]
-fn get_server(position: Json<Vec3>, session: &State<Session>) -> String {
-    let server_index = session.read().unwrap().query(*position + (WORLD_SIZE / 2.), WORLD_SIZE); // Add by WORLD_SIZE / 2 to put everything in positive coord system
+fn get_server(position: Json<Vec3>, routing: &State<SharedRouting>, metrics: &State<Arc<Metrics>>) -> String {
+    metrics.queries.fetch_add(1, Ordering::Relaxed);
+    // Players only talk to the server that owns their position, it passes them on to neighbours near the border
+    let server_index: HashSet<usize> = [routing.read().unwrap().table.owner(*position)].into_iter().collect();
     serde_json::to_string(&server_index).unwrap()
 }
 
-
-#[derive(Parser, Debug)]
-#[clap(name = "Server")]
-struct Args {
-    /// The port to run on
-    #[clap(short, long)]
-    port: i32,
+/// The region covered by the whole world
+pub fn world_region() -> Aabb {
+    Aabb::new(Vec3::ONE * -WORLD_SIZE / 2., Vec3::ONE * WORLD_SIZE / 2.)
 }
 
-pub type Session = Arc<RwLock<Server>>;
+/// Ask a server how many players it has
+pub fn fetch_population(address: &ServerAddress, secret: &AuthSecret) -> reqwest::Result<usize> {
+    reqwest::blocking::Client::new().get(format!("{}/get_num_players", address.http))
+        .header(AUTH_HEADER, &secret.0)
+        .send()?.error_for_status()?.json()
+}
 
-#[derive(Debug)]
-pub enum Server {
-    Octree([[[Box<Server>; 2]; 2]; 2]),
-    Num(usize, usize) // Contains index and population
+/// Address of a server in the tree, logging it if there isn't one
+fn address_of(addresses: &[Option<ServerAddress>], index: usize) -> Option<&ServerAddress> {
+    let address = addresses.get(index).and_then(|a| a.as_ref());
+    if address.is_none() {
+        eprintln!("Server {} in use has no address", index);
+    }
+    address
 }
 
-impl Server {
-    /// Get smallest server this position is inside
-    pub fn query(&self, position: Vec3, size: f32) -> HashSet<usize> {
-        match self {
-            Self::Octree(a) => {
-                let half_size = size / 2.;
-                let (x_index, y_index, z_index) = (((position.x / half_size) as usize).clamp(0, 1), ((position.y / half_size) as usize).clamp(0, 1), ((position.z / half_size) as usize).clamp(0, 1));
-                let mut main = a[x_index][y_index][z_index].query(position, half_size);
-                if ((position.x - half_size) / half_size).abs() < BORDER_BUFFER_SIZE {
-                    // X crossover, get other chunk
-                    let x_adj_index = if (position.x % half_size) as usize == 0 {1} else {0};
-                    main.extend(&a[x_adj_index][y_index][z_index].query(position, half_size));
-                }
-                if ((position.y - half_size) / half_size).abs() < BORDER_BUFFER_SIZE {
-                    // Y crossover, get other chunk
-                    let y_adj_index = if (position.y % half_size) as usize == 0 {1} else {0};
-                    main.extend(&a[x_index][y_adj_index][z_index].query(position, half_size));
-                }
-                if ((position.z - half_size) / half_size).abs() < BORDER_BUFFER_SIZE {
-                    // Z crossover, get other chunk
-                    let z_adj_index = if (position.z % half_size) as usize == 0 {1} else {0};
-                    main.extend(&a[x_index][y_index][z_adj_index].query(position, half_size));
-                }
-                main
-            },
-            Self::Num(i, _) => [*i].into_iter().collect()
+/// Ask every server in the tree how many players it has. Servers that don't answer are left out, so they keep the last population we had
+fn fetch_populations(leaves: &[(Aabb, usize)], addresses: &[Option<ServerAddress>], secret: &AuthSecret) -> HashMap<usize, usize> {
+    let indices: HashSet<usize> = leaves.iter().map(|(_, i)| *i).collect();
+    let mut populations = HashMap::new();
+    for index in indices {
+        let address = match address_of(addresses, index) {
+            Some(a) => a,
+            None => continue,
+        };
+        match fetch_population(address, secret) {
+            Ok(population) => {populations.insert(index, population);},
+            Err(e) => eprintln!("Failed to get population from server {}: {}", index, e),
         }
     }
+    populations
+}
 
-    /// Go through each server and get an updated population count
-    pub fn update_population(&mut self) {
-        match self {
-            Self::Octree(a) => {
-                for x in a {
-                    for y in x {
-                        for z in y {
-                            z.update_population();
-                        }
-                    }
-                }
-            },
-            Self::Num(i, pop) => {
-                // Update population of this server
-                *pop = reqwest::blocking::get(format!("{}/get_num_players", crate::SERVER_ADDRESSES[*i]))
-                    .unwrap().json().unwrap();
-            }
+/// Get a density histogram for every region with too many players, so splits can be planned
+fn fetch_densities(leaves: &[(Aabb, usize)], populations: &HashMap<usize, usize>, addresses: &[Option<ServerAddress>], secret: &AuthSecret) -> Vec<(Aabb, DensityHistogram)> {
+    let mut densities = vec![];
+    for (region, index) in leaves {
+        if populations.get(index).is_none_or(|p| *p <= MAX_PLAYERS) {continue;}
+        let address = match address_of(addresses, *index) {
+            Some(a) => a,
+            None => continue,
+        };
+        match fetch_density(address, *region, secret) {
+            Ok(density) => densities.push((*region, density)),
+            Err(e) => eprintln!("Failed to get density from server {}: {}", index, e), // Split without it
         }
     }
+    densities
+}
 
-    // Try to free up servers based on population numbers based on population numbers
-    pub fn restructure_free(&mut self, free_servers: &mut Vec<bool>) {
-        // Attempt to merge two blocks (pop is population of block1, index is index of block1) SUPER UGLY
-        fn try_merge(parent_block: &mut [[[Box<Server>; 2]; 2]; 2], block1_coords: [usize; 3], block2_coords: [usize; 3], free_servers: &mut Vec<bool>) {
-            if let Some(index) = parent_block[block1_coords[0]][block1_coords[1]][block1_coords[2]].get_index() {
-                let mut pop = parent_block[block1_coords[0]][block1_coords[1]][block1_coords[2]].get_population().unwrap();
-                if !parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].is_octree() && parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].get_index().unwrap() != index { // If adjacent block is not an octree and is not already merged with this block
-                    if parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].get_population().unwrap() + pop < MAX_PLAYERS { // Merge into this block and free server
-                        pop += parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].get_population().unwrap();
-                        free_servers[parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].get_index().unwrap()] = true; // Free the server
-                        parent_block[block1_coords[0]][block1_coords[1]][block1_coords[2]].try_update(index, pop);
-                        parent_block[block2_coords[0]][block2_coords[1]][block2_coords[2]].try_update(index, pop);
-                    }
-                }
-            }
-        }
+/// Ask a server where its players in a region are
+pub fn fetch_density(address: &ServerAddress, region: Aabb, secret: &AuthSecret) -> reqwest::Result<DensityHistogram> {
+    reqwest::blocking::Client::new().post(format!("{}/get_density", address.http))
+        .header(AUTH_HEADER, &secret.0)
+        .json(&region)
+        .send()?.error_for_status()?.json()
+}
 
-        match self {
-            Self::Octree(a) => {
-                // Loop through octree to see if we can combine blocks
-                let (mut one_block, first_index) = (true, a[0][0][0].get_index().unwrap());
-                for x in 0..2 {
-                    for y in 0..2 {
-                        for z in 0..2 {
-                            if a[x][y][z].is_octree() {
-                                a[x][y][z].restructure_free(free_servers);
-                            } else {
-                                // There are 3 adjacent blocks for each block
-                                // Other block along x
-                                let adj_x = if x == 0 {1} else {0};
-                                try_merge(a, [x, y, z], [adj_x, y, z], free_servers);
-                                
-                                // Other block along y
-                                let adj_y = if y == 0 {1} else {0};
-                                try_merge(a, [x, y, z], [x, adj_y, z], free_servers);
-
-                                // Other block along z
-                                let adj_z = if z == 0 {1} else {0};
-                                try_merge(a, [x, y, z], [x, y, adj_z], free_servers);
-                            }
-
-                            if a[x][y][z].is_octree() || a[x][y][z].get_index().unwrap() != first_index {
-                                one_block = false;
-                            }
-                        }
-                    }
-                }
+/// Get the address of every server, indexed by server number (terminated servers are null)
+#[get("/get_servers")]
+fn get_servers(servers: &State<ServerList>) -> String {
+    serde_json::to_string(&*servers.read().unwrap()).unwrap()
+}
 
-                if one_block {
-                    // Merge into one block
-                    *self = Self::Num(first_index, a[0][0][0].get_population().unwrap());
-                }
-            },
-            Self::Num(_, _) => {} // If we are just one server, nothing we can do
-        }
-    }
 
-    // Allocate more servers if nessacary and more are availiable
-    pub fn restructure_allocate(&mut self, free_servers: &mut Vec<bool>) {
-        /// Try to split a block with the free servers availiable (naievely use all availiable free servers we need)
-        pub fn try_split(block: &mut Server, free_servers: &mut Vec<bool>) {
-            if block.is_octree() {return;}
-            let index = block.get_population().unwrap();
-            let mut a = [[[Server::Num(0, 0), Server::Num(0, 0)], [Server::Num(0, 0), Server::Num(0, 0)]], [[Server::Num(0, 0), Server::Num(0, 0)], [Server::Num(0, 0), Server::Num(0, 0)]]];
-            // Assign servers and populations
-            #[allow(clippy::needless_range_loop)]
-            for x in 0..2 {
-                for y in 0..2 {
-                    for z in 0..2 {
-                        if x == 0 && y == 0 && z == 0 { // Keep index for first server
-                            a[x][y][z].try_update(index, 0);
-                        } else {
-                            let free_server = free_servers.iter().enumerate().find(|(_, b)| **b);
-                            if let Some((index, _)) = free_server {
-                                a[x][y][z].try_update(index, 0);
-                                free_servers[index] = false;
-                            } else {
-                                a[x][y][z].try_update(index, 0);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+#[derive(Parser, Debug)]
+#[clap(name = "Server")]
+struct Args {
+    /// The port to run on
+    #[clap(short, long)]
+    port: i32,
 
-        match self {
-            Self::Octree(a) => {
-                // Loop through octree to see if we can combine blocks
-                for x in a{
-                    for y in x {
-                        for z in y {
-                            if z.is_octree() {
-                                z.restructure_allocate(free_servers);
-                            } else if z.get_population().unwrap() > MAX_PLAYERS {
-                                // Try to split
-                                try_split(z, free_servers);
-                            }
-                        }
-                    }
-                }
-            },
-            Self::Num(_, pop) => {
-                if *pop > MAX_PLAYERS {
-                    // Try to split
-                    try_split(self, free_servers);
-                }
-            }
-        }
-    }
+    /// The UDP port to answer binary server lookups on
+    #[clap(long, default_value = "8003")]
+    query_port: i32,
 
-    pub fn is_octree(&self) -> bool {
-        match self {
-            Self::Octree(_) => true,
-            Self::Num(_, _) => false
-        }
-    }
+    /// Path to the server binary, enables spawning servers when the free pool runs low
+    #[clap(long)]
+    server_binary: Option<PathBuf>,
 
-    pub fn get_population(&self) -> Option<usize> {
-        match self {
-            Self::Octree(_) => None,
-            Self::Num(_, pop) => Some(*pop)
-        }
-    }
+    /// Secret shared with every server, used to authenticate requests between them. Best set through the environment, where other users can't see it like they can the command line
+    #[clap(long, env = "GAME_SECRET", hide_env_values = true)]
+    secret: String,
 
-    pub fn get_index(&self) -> Option<usize> {
-        match self {
-            Self::Octree(_) => None,
-            Self::Num(i, _) => Some(*i)
-        }
-    }
+    /// File to append restructuring events to, as JSON lines
+    #[clap(long)]
+    event_log: Option<PathBuf>,
 
-    pub fn try_update(&mut self, new_index: usize, new_pop: usize) {
-        match self {
-            Self::Octree(_) => {},
-            Self::Num(i, pop) => {
-                *i = new_index;
-                *pop = new_pop;
-            }
-        }
-    }
-}
\ No newline at end of file
+    /// Never terminate servers below this many
+    #[clap(long, default_value = "2")]
+    min_pool: usize,
+
+    /// Never spawn servers above this many
+    #[clap(long, default_value = "64")]
+    max_pool: usize,
+
+    /// How many free servers to keep around for splitting
+    #[clap(long, default_value = "7")]
+    min_free: usize,
+}
+
+pub type Session = Arc<RwLock<Server>>;
+pub type ServerList = Arc<RwLock<Vec<Option<ServerAddress>>>>;
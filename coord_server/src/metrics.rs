@@ -0,0 +1,49 @@
+use std::{sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}, time::Duration};
+
+use game_structs::metrics::{write_metric, MetricKind};
+use rocket::{get, State};
+
+use crate::Session;
+
+/// Counters and gauges exposed on the /metrics endpoint
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub servers_in_use: AtomicUsize,
+    pub servers_free: AtomicUsize,
+    pub restructure_count: AtomicU64,
+    pub restructure_micros_total: AtomicU64,
+    pub restructure_micros_last: AtomicU64,
+    pub queries: AtomicU64,
+    pub bad_queries: AtomicU64,
+}
+
+impl Metrics {
+    /// Record how long a restructuring pass took
+    pub fn record_restructure(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        self.restructure_count.fetch_add(1, Ordering::Relaxed);
+        self.restructure_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.restructure_micros_last.store(micros, Ordering::Relaxed);
+    }
+
+    /// Record the current usage of the server pool
+    pub fn record_pool(&self, free_servers: &[bool]) {
+        let free = free_servers.iter().filter(|f| **f).count();
+        self.servers_free.store(free, Ordering::Relaxed);
+        self.servers_in_use.store(free_servers.len() - free, Ordering::Relaxed);
+    }
+}
+
+#[get("/metrics")]
+pub fn get_metrics(session: &State<Session>, metrics: &State<Arc<Metrics>>) -> String {
+    let mut out = String::new();
+    write_metric(&mut out, "coord_tree_depth", "Depth of the server octree", MetricKind::Gauge, session.read().unwrap().depth());
+    write_metric(&mut out, "coord_servers_in_use", "Servers currently assigned to a region", MetricKind::Gauge, metrics.servers_in_use.load(Ordering::Relaxed));
+    write_metric(&mut out, "coord_servers_free", "Servers waiting in the free pool", MetricKind::Gauge, metrics.servers_free.load(Ordering::Relaxed));
+    write_metric(&mut out, "coord_restructure_total", "Restructuring passes run", MetricKind::Counter, metrics.restructure_count.load(Ordering::Relaxed));
+    write_metric(&mut out, "coord_restructure_duration_seconds_total", "Total time spent restructuring", MetricKind::Counter, metrics.restructure_micros_total.load(Ordering::Relaxed) as f64 / 1e6);
+    write_metric(&mut out, "coord_restructure_last_duration_seconds", "Duration of the latest restructuring pass", MetricKind::Gauge, metrics.restructure_micros_last.load(Ordering::Relaxed) as f64 / 1e6);
+    write_metric(&mut out, "coord_queries_total", "Server lookups answered", MetricKind::Counter, metrics.queries.load(Ordering::Relaxed));
+    write_metric(&mut out, "coord_bad_queries_total", "UDP queries that couldn't be decoded", MetricKind::Counter, metrics.bad_queries.load(Ordering::Relaxed));
+    out
+}
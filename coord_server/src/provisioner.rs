@@ -0,0 +1,154 @@
+use std::{collections::HashMap, net::{TcpListener, UdpSocket}, path::PathBuf, process::{Child, Command}, thread, time::Duration};
+
+use game_structs::{ServerAddress, auth::AuthSecret};
+
+static SPAWN_ATTEMPTS: usize = 3; // Times to start a server on fresh ports if it exits before coming up
+
+/// Something that can start and stop servers for the pool
+pub trait Provisioner: Send {
+    /// Start a new server, returning its address once it is ready to take requests
+    fn spawn(&mut self) -> Option<ServerAddress>;
+
+    /// Stop a server, returning false if this provisioner doesn't own it
+    fn terminate(&mut self, address: &ServerAddress) -> bool;
+}
+
+/// Limits on how big the pool of servers can grow or shrink
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Never terminate servers below this many
+    pub min_size: usize,
+    /// Never spawn servers above this many
+    pub max_size: usize,
+    /// Keep at least this many servers free so regions can split
+    pub min_free: usize,
+}
+
+/// Spawn servers when the free pool runs low and terminate idle ones when there are too many
+pub fn balance_pool(provisioner: &mut dyn Provisioner, config: &PoolConfig, secret: &AuthSecret, free_servers: &mut Vec<bool>, addresses: &mut Vec<Option<ServerAddress>>) {
+    let pool_size = |addresses: &[Option<ServerAddress>]| addresses.iter().filter(|a| a.is_some()).count();
+    let free_count = |free_servers: &[bool]| free_servers.iter().filter(|f| **f).count();
+
+    // Grow the pool
+    while free_count(free_servers) < config.min_free && pool_size(addresses) < config.max_size {
+        let address = match provisioner.spawn() {
+            Some(a) => a,
+            None => break,
+        };
+        // Reuse the slot of a terminated server if there is one
+        if let Some(index) = addresses.iter().position(|a| a.is_none()) {
+            addresses[index] = Some(address);
+            free_servers[index] = true;
+        } else {
+            addresses.push(Some(address));
+            free_servers.push(true);
+        }
+    }
+
+    // Shrink the pool, only terminating free servers nobody is connected to anymore
+    for index in 0..free_servers.len() {
+        if free_count(free_servers) <= config.min_free || pool_size(addresses) <= config.min_size {
+            break;
+        }
+        if !free_servers[index] {continue;}
+        if let Some(address) = addresses[index].clone() {
+            let idle = crate::fetch_population(&address, secret)
+                .map(|pop| pop == 0)
+                .unwrap_or(true); // Unreachable servers aren't serving anybody
+            if idle && provisioner.terminate(&address) {
+                addresses[index] = None;
+                free_servers[index] = false;
+            }
+        }
+    }
+}
+
+/// Runs servers as child processes on this machine
+pub struct LocalProvisioner {
+    binary: PathBuf,
+    secret: AuthSecret,
+    coord_address: String,
+    children: HashMap<String, Child>,
+}
+
+impl LocalProvisioner {
+    pub fn new(binary: PathBuf, secret: AuthSecret, coord_address: String) -> Self {
+        Self {
+            binary,
+            secret,
+            coord_address,
+            children: HashMap::new(),
+        }
+    }
+
+    /// Start a server process on ports that are free right now
+    fn start(&self) -> Option<(Child, ServerAddress)> {
+        // Let the OS pick unused ports
+        let send = UdpSocket::bind("127.0.0.1:0").ok()?.local_addr().ok()?.port();
+        let receive = UdpSocket::bind("127.0.0.1:0").ok()?.local_addr().ok()?.port();
+        let main = TcpListener::bind("127.0.0.1:0").ok()?.local_addr().ok()?.port();
+
+        let child = Command::new(&self.binary)
+            .arg(format!("--send={}", send))
+            .arg(format!("--receive={}", receive))
+            .arg(format!("--main={}", main))
+            .arg(format!("--coord={}", self.coord_address))
+            .env("GAME_SECRET", &self.secret.0) // Kept off the command line, which any local user can read
+            .spawn().ok()?;
+        let address = ServerAddress {
+            http: format!("http://127.0.0.1:{}", main),
+            udp: format!("127.0.0.1:{}", receive),
+        };
+        Some((child, address))
+    }
+}
+
+impl Provisioner for LocalProvisioner {
+    fn spawn(&mut self) -> Option<ServerAddress> {
+        // The ports are free when we pick them, but another process can take one before the server binds it.
+        // The server exits if it can't bind, so start it again on new ports
+        for _ in 0..SPAWN_ATTEMPTS {
+            let (mut child, address) = self.start()?;
+
+            // Wait for the server to come up
+            let mut exited = false;
+            for _ in 0..50 {
+                if let Ok(Some(_)) = child.try_wait() {
+                    exited = true;
+                    break;
+                }
+                if reqwest::blocking::get(format!("{}/get_num_players", address.http)).is_ok() {
+                    self.children.insert(address.http.clone(), child);
+                    return Some(address);
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+            if !exited {
+                // Running but never answered, something other than the ports is wrong
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+        None
+    }
+
+    fn terminate(&mut self, address: &ServerAddress) -> bool {
+        if let Some(mut child) = self.children.remove(&address.http) {
+            let _ = child.kill();
+            let _ = child.wait();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Drop for LocalProvisioner {
+    fn drop(&mut self) {
+        for child in self.children.values_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
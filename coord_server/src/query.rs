@@ -0,0 +1,32 @@
+use std::{net::UdpSocket, sync::{Arc, atomic::Ordering}};
+
+use game_structs::operations::{CoordRequest, CoordResponse};
+
+use crate::{metrics::Metrics, routing::SharedRouting};
+
+/// Answer binary server lookups over UDP, a cheaper alternative to /get_server for clients polling often
+pub fn serve_queries(socket: UdpSocket, routing: SharedRouting, metrics: Arc<Metrics>) {
+    let mut buf = [0; 512];
+    loop {
+        let (amt, src) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Failed to receive query: {}", e);
+                continue;
+            }
+        };
+        let response = match bincode::deserialize(&buf[..amt]) {
+            Ok(CoordRequest::GetServer { request_id, position }) => {
+                metrics.queries.fetch_add(1, Ordering::Relaxed);
+                CoordResponse::Servers { request_id, servers: [routing.read().unwrap().table.owner(position)].into_iter().collect() }
+            },
+            Err(_) => {
+                metrics.bad_queries.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        if let Err(e) = socket.send_to(&bincode::serialize(&response).unwrap(), src) {
+            eprintln!("Failed to answer query from {}: {}", src, e);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{collections::VecDeque, sync::{Arc, RwLock}};
+
+use game_structs::routing::{RegionNode, RoutingTable, RoutingUpdate};
+use rocket::{get, http::Status, State};
+use uuid::Uuid;
+
+use crate::{BORDER_BUFFER_SIZE, world_region};
+
+static ROUTING_UPDATES_KEPT: usize = 100; // How many updates to keep for clients catching up
+
+pub type SharedRouting = Arc<RwLock<Routing>>;
+
+/// The published copy of the region tree, and the recent changes made to it
+pub struct Routing {
+    pub table: RoutingTable,
+    updates: VecDeque<RoutingUpdate>,
+}
+
+impl Routing {
+    pub fn new(root: RegionNode) -> Self {
+        Self {
+            table: RoutingTable { generation: Uuid::new_v4().as_u128() as u64, epoch: 0, region: world_region(), border_buffer: BORDER_BUFFER_SIZE, root },
+            updates: VecDeque::new(),
+        }
+    }
+
+    /// Publish the tree after restructuring, starting a new epoch if anything changed
+    pub fn publish(&mut self, root: RegionNode) {
+        let mut changes = vec![];
+        self.table.root.diff(&root, &mut vec![], &mut changes);
+        if changes.is_empty() {return;}
+
+        let update = RoutingUpdate { generation: self.table.generation, epoch: self.table.epoch + 1, changes };
+        self.table.apply(&update);
+        self.updates.push_back(update);
+        if self.updates.len() > ROUTING_UPDATES_KEPT {
+            self.updates.pop_front();
+        }
+    }
+
+    /// Updates needed to bring a table at this epoch up to date, or None if they are no longer kept or the table is from another generation
+    pub fn updates_since(&self, generation: u64, epoch: u64) -> Option<Vec<RoutingUpdate>> {
+        if generation != self.table.generation || epoch > self.table.epoch {return None;}
+        let oldest = self.updates.front().map_or(self.table.epoch + 1, |u| u.epoch);
+        if epoch + 1 < oldest {return None;}
+        Some(self.updates.iter().filter(|u| u.epoch > epoch).cloned().collect())
+    }
+}
+
+/// The whole routing table, for clients that want to look up servers themselves
+#[get("/get_routing")]
+pub fn get_routing(routing: &State<SharedRouting>) -> String {
+    serde_json::to_string(&routing.read().unwrap().table).unwrap()
+}
+
+/// Changes made to the routing table after an epoch, 410 if the client is too far behind or has a table from before the coord restarted,
+/// and should download it again
+#[get("/get_routing_updates?<generation>&<since>")]
+pub fn get_routing_updates(routing: &State<SharedRouting>, generation: u64, since: u64) -> Result<String, Status> {
+    match routing.read().unwrap().updates_since(generation, since) {
+        Some(updates) => Ok(serde_json::to_string(&updates).unwrap()),
+        None => Err(Status::Gone),
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+
+use game_structs::{Vec3, Aabb, density::DensityHistogram, routing::RegionNode};
+
+use crate::{MAX_PLAYERS, MAX_SPLIT_SHARE, events::EventKind, constraints::{Constraints, PinCoverage}};
+
+#[derive(Debug)]
+pub enum Server {
+    Octree(Vec3, [[[Box<Server>; 2]; 2]; 2]), // Contains the split point and the eight octants
+    Num(usize, usize) // Contains index and population
+}
+
+impl Server {
+    /// Copy of the tree to publish to clients
+    pub fn to_region_node(&self) -> RegionNode {
+        match self {
+            Self::Octree(split, a) => RegionNode::Split {
+                split: *split,
+                children: a.iter().flatten().flatten().map(|b| b.to_region_node()).collect()
+            },
+            Self::Num(i, _) => RegionNode::Leaf { index: *i }
+        }
+    }
+
+    /// Give every block the population its server reported, blocks whose server didn't answer keep the last one
+    pub fn update_population(&mut self, populations: &HashMap<usize, usize>) {
+        match self {
+            Self::Octree(_, a) => a.iter_mut().flatten().flatten().for_each(|b| b.update_population(populations)),
+            Self::Num(i, pop) => {
+                if let Some(population) = populations.get(i) {
+                    *pop = *population;
+                }
+            }
+        }
+    }
+
+    // Try to free up servers based on population numbers based on population numbers. Merged servers can still be
+    // running blocks further down the tree, so they are only returned to the pool once nothing in the tree uses them
+    pub fn restructure_free(&mut self, region: Aabb, constraints: &Constraints, events: &mut Vec<EventKind>) {
+        // Attempt to merge block2 into block1, moving block2's server's blocks at this level over to block1's
+        fn try_merge(parent_block: &mut [[[Box<Server>; 2]; 2]; 2], region: Aabb, split: Vec3, block1_coords: [usize; 3], block2_coords: [usize; 3], constraints: &Constraints, events: &mut Vec<EventKind>) {
+            let [x1, y1, z1] = block1_coords;
+            let [x2, y2, z2] = block2_coords;
+            if constraints.is_locked(region.octant(split, x1, y1, z1)) || constraints.is_locked(region.octant(split, x2, y2, z2)) {return;}
+            let (index, pop) = match (parent_block[x1][y1][z1].get_index(), parent_block[x1][y1][z1].get_population()) {
+                (Some(i), Some(p)) => (i, p),
+                _ => return,
+            };
+            let (index2, pop2) = match (parent_block[x2][y2][z2].get_index(), parent_block[x2][y2][z2].get_population()) {
+                (Some(i), Some(p)) => (i, p),
+                _ => return, // Adjacent block is an octree
+            };
+            if index2 == index || pop + pop2 >= MAX_PLAYERS {return;} // Already merged, or too many players to merge
+
+            // Merge into this block, moving every block on the old server over
+            let pop = pop + pop2;
+            for block in parent_block.iter_mut().flatten().flatten() {
+                if matches!(block.get_index(), Some(i) if i == index || i == index2) {
+                    block.try_update(index, pop);
+                }
+            }
+            events.push(EventKind::Merge {
+                region: region.octant(split, x2, y2, z2),
+                index,
+                merged_index: index2,
+                population: pop,
+            });
+        }
+
+        match self {
+            Self::Octree(split, a) => {
+                let split = *split;
+                // Loop through octree to see if we can combine blocks
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            if a[x][y][z].is_octree() {
+                                a[x][y][z].restructure_free(region.octant(split, x, y, z), constraints, events);
+                            } else {
+                                // There are 3 adjacent blocks for each block
+                                // Other block along x
+                                let adj_x = if x == 0 {1} else {0};
+                                try_merge(a, region, split, [x, y, z], [adj_x, y, z], constraints, events);
+                                
+                                // Other block along y
+                                let adj_y = if y == 0 {1} else {0};
+                                try_merge(a, region, split, [x, y, z], [x, adj_y, z], constraints, events);
+
+                                // Other block along z
+                                let adj_z = if z == 0 {1} else {0};
+                                try_merge(a, region, split, [x, y, z], [x, y, adj_z], constraints, events);
+                            }
+                        }
+                    }
+                }
+
+                // If every block ended up on the same server, merge into one block
+                if constraints.is_locked(region) {return;}
+                if let (Some(first_index), Some(pop)) = (a[0][0][0].get_index(), a[0][0][0].get_population()) {
+                    if a.iter().flatten().flatten().all(|b| b.get_index() == Some(first_index)) {
+                        *self = Self::Num(first_index, pop);
+                    }
+                }
+            },
+            Self::Num(_, _) => {} // If we are just one server, nothing we can do
+        }
+    }
+
+    // Allocate more servers if nessacary and more are availiable
+    pub fn restructure_allocate(&mut self, region: Aabb, free_servers: &mut [bool], constraints: &Constraints, densities: &[(Aabb, DensityHistogram)], events: &mut Vec<EventKind>) {
+        /// Try to split a block where its players are, giving a free server to each octant expected to have players
+        pub fn try_split(block: &mut Server, region: Aabb, free_servers: &mut [bool], densities: &[(Aabb, DensityHistogram)], events: &mut Vec<EventKind>) {
+            let (index, population) = match block {
+                Server::Octree(_, _) => return,
+                Server::Num(i, pop) => (*i, *pop),
+            };
+
+            // Split through the middle of the players if we know where they are, otherwise through the middle of the region
+            let (split, predicted) = match densities.iter().find(|(r, _)| *r == region).map(|(_, d)| d) {
+                Some(density) if density.total() > 0 => {
+                    let (split, cells) = density.split_point();
+                    let total = density.total();
+                    // Scale up to the full population, the histogram only has players with a known position
+                    (split, density.octant_counts(cells).iter().map(|c| c * population / total).collect::<Vec<_>>())
+                },
+                _ => (region.center(), vec![]),
+            };
+            if predicted.iter().any(|p| *p as f32 > population as f32 * MAX_SPLIT_SHARE) {
+                // Nearly everyone would stay in one octant, so splitting wouldn't take load off this server
+                events.push(EventKind::SkippedSplit { region, index, population, predicted });
+                return;
+            }
+            if !free_servers.iter().any(|f| *f) {
+                // Splitting without any new servers wouldn't take load off this one
+                events.push(EventKind::FailedAllocation { region, index, population });
+                return;
+            }
+
+            // The busiest octant keeps the old server so the fewest players have to move
+            let keep = predicted.iter().enumerate().max_by_key(|(_, p)| **p).map(|(i, _)| i).unwrap_or(0);
+            let mut a = [[[Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))], [Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))]], [[Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))], [Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))]]];
+            let mut children = vec![];
+            #[allow(clippy::needless_range_loop)]
+            for x in 0..2 {
+                for y in 0..2 {
+                    for z in 0..2 {
+                        let octant = (x * 2 + y) * 2 + z;
+                        // Octants expected to be empty don't need a server of their own
+                        if octant == keep || predicted.get(octant) == Some(&0) {
+                            children.push(index);
+                            continue;
+                        }
+                        if let Some(free_index) = free_servers.iter().position(|f| *f) {
+                            a[x][y][z].try_update(free_index, 0);
+                            free_servers[free_index] = false;
+                            events.push(EventKind::Allocate { region: region.octant(split, x, y, z), index: free_index });
+                        } // Otherwise the block stays on the old server
+                        children.push(a[x][y][z].get_index().unwrap());
+                    }
+                }
+            }
+            *block = Server::Octree(split, a);
+            events.push(EventKind::Split { region, index, population, split, children, predicted });
+        }
+
+        /// Split a block on the same server so a pin can take over part of it. The server keeps running every octant the
+        /// pin doesn't take, so merging one of them away doesn't free it
+        fn split_for_pin(block: &mut Server, region: Aabb, events: &mut Vec<EventKind>) {
+            if let Server::Num(index, population) = *block {
+                let a = [[[Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))], [Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))]], [[Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))], [Box::new(Server::Num(index, 0)), Box::new(Server::Num(index, 0))]]];
+                let split = region.center();
+                *block = Server::Octree(split, a);
+                events.push(EventKind::Split { region, index, population, split, children: vec![index; 8], predicted: vec![] });
+            }
+        }
+
+        // Pins take priority over population
+        if let Self::Num(index, pop) = self {
+            match constraints.pin_coverage(region) {
+                PinCoverage::Pinned(pinned_index) => {
+                    if *index != pinned_index {
+                        events.push(EventKind::Allocate { region, index: pinned_index });
+                        *index = pinned_index;
+                        *pop = 0;
+                    }
+                    return; // Pinned regions are never split
+                },
+                PinCoverage::Partial => {
+                    split_for_pin(self, region, events);
+                    self.restructure_allocate(region, free_servers, constraints, densities, events);
+                    return;
+                },
+                PinCoverage::None => {}
+            }
+        }
+
+        match self {
+            Self::Octree(split, a) => {
+                // Loop through octree to see if we need to split blocks
+                for (x, a) in a.iter_mut().enumerate() {
+                    for (y, a) in a.iter_mut().enumerate() {
+                        for (z, block) in a.iter_mut().enumerate() {
+                            block.restructure_allocate(region.octant(*split, x, y, z), free_servers, constraints, densities, events);
+                        }
+                    }
+                }
+            },
+            Self::Num(_, pop) => {
+                if *pop > MAX_PLAYERS {
+                    // Try to split
+                    try_split(self, region, free_servers, densities, events);
+                }
+            }
+        }
+    }
+
+    /// List the region and server of every leaf in the tree
+    pub fn leaves(&self, region: Aabb, out: &mut Vec<(Aabb, usize)>) {
+        match self {
+            Self::Octree(split, a) => {
+                for (x, a) in a.iter().enumerate() {
+                    for (y, a) in a.iter().enumerate() {
+                        for (z, block) in a.iter().enumerate() {
+                            block.leaves(region.octant(*split, x, y, z), out);
+                        }
+                    }
+                }
+            },
+            Self::Num(i, _) => out.push((region, *i))
+        }
+    }
+
+    /// Add the index of every server used in the tree
+    pub fn indices(&self, out: &mut HashSet<usize>) {
+        match self {
+            Self::Octree(_, a) => a.iter().flatten().flatten().for_each(|b| b.indices(out)),
+            Self::Num(i, _) => {out.insert(*i);}
+        }
+    }
+
+    /// Number of levels in the tree below and including this node
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Octree(_, a) => 1 + a.iter().flatten().flatten().map(|s| s.depth()).max().unwrap_or(0),
+            Self::Num(_, _) => 1
+        }
+    }
+
+    pub fn is_octree(&self) -> bool {
+        match self {
+            Self::Octree(..) => true,
+            Self::Num(_, _) => false
+        }
+    }
+
+    pub fn get_population(&self) -> Option<usize> {
+        match self {
+            Self::Octree(..) => None,
+            Self::Num(_, pop) => Some(*pop)
+        }
+    }
+
+    pub fn get_index(&self) -> Option<usize> {
+        match self {
+            Self::Octree(..) => None,
+            Self::Num(i, _) => Some(*i)
+        }
+    }
+
+    pub fn try_update(&mut self, new_index: usize, new_pop: usize) {
+        match self {
+            Self::Octree(..) => {},
+            Self::Num(i, pop) => {
+                *i = new_index;
+                *pop = new_pop;
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use game_structs::density::DENSITY_RESOLUTION;
+
+    use super::*;
+    use crate::{world_region, constraints::{Pin, PinStatus}};
+
+    /// Players bunched up around each point, as a server would report them
+    fn density(region: Aabb, clusters: &[(Vec3, usize)]) -> (Aabb, DensityHistogram) {
+        let positions = clusters.iter().flat_map(|(p, n)| std::iter::repeat_n(*p, *n));
+        (region, DensityHistogram::new(region, DENSITY_RESOLUTION, positions))
+    }
+
+    fn set_populations(block: &mut Server, population: usize) {
+        match block {
+            Server::Octree(_, a) => a.iter_mut().flatten().flatten().for_each(|b| set_populations(b, population)),
+            Server::Num(_, pop) => *pop = population,
+        }
+    }
+
+    fn octant(block: &mut Server, x: usize, y: usize, z: usize) -> (&mut Server, Vec3) {
+        match block {
+            Server::Octree(split, a) => (&mut a[x][y][z], *split),
+            Server::Num(..) => panic!("Block wasn't split"),
+        }
+    }
+
+    fn assert_used_servers_not_free(root: &Server, free_servers: &[bool]) {
+        let mut used = HashSet::new();
+        root.indices(&mut used);
+        for index in used {
+            assert!(!free_servers[index], "Server {} was freed while it still has a region", index);
+        }
+    }
+
+    #[test]
+    fn merging_doesnt_free_a_server_still_used_further_down() {
+        let world = world_region();
+        let constraints = Constraints::default();
+        let mut free_servers = vec![false, true, true, true];
+        let mut events = vec![];
+
+        // Most players in the top corner, so that octant keeps server 0 along with the empty ones
+        let mut root = Server::Num(0, 150);
+        let densities = [density(world, &[(Vec3::ONE * 300., 100), (Vec3::ONE * -300., 50)])];
+        root.restructure_allocate(world, &mut free_servers, &constraints, &densities, &mut events);
+        let (kept, split) = octant(&mut root, 1, 1, 1);
+        assert_eq!(kept.get_index(), Some(0));
+
+        // The kept octant splits again, keeping server 0 on one of its own octants
+        let kept_region = world.octant(split, 1, 1, 1);
+        kept.try_update(0, 150);
+        let densities = [density(kept_region, &[(Vec3::ONE * 400., 100), (Vec3::ONE * 100., 50)])];
+        root.restructure_allocate(world, &mut free_servers, &constraints, &densities, &mut events);
+        assert!(octant(&mut root, 1, 1, 1).0.is_octree());
+
+        // Emptying out lets server 1 take over the empty octants still on server 0
+        set_populations(&mut root, 10);
+        events.clear();
+        root.restructure_free(world, &constraints, &mut events);
+        assert!(events.iter().any(|e| matches!(e, EventKind::Merge { merged_index: 0, .. })));
+        assert_used_servers_not_free(&root, &free_servers);
+    }
+
+    #[test]
+    fn merging_next_to_a_pin_doesnt_free_the_server_split_around_it() {
+        let world = world_region();
+        let mut constraints = Constraints::default();
+        let pinned = Aabb::new(Vec3::ONE * -512., Vec3::ONE * -496.);
+        constraints.pins.push(Pin { id: 1, region: pinned, index: 3, status: PinStatus::Active });
+        let mut free_servers = vec![false, true, true, false];
+        let mut events = vec![];
+
+        // Splitting down to the pin leaves server 0 on every other block
+        let mut root = Server::Num(0, 0);
+        root.restructure_allocate(world, &mut free_servers, &constraints, &[], &mut events);
+        let mut leaves = vec![];
+        root.leaves(world, &mut leaves);
+        assert!(leaves.contains(&(pinned, 3)));
+
+        // A block away from the pin splits, giving part of it to server 1
+        let (block, split) = octant(&mut root, 1, 1, 1);
+        let region = world.octant(split, 1, 1, 1);
+        block.try_update(0, 150);
+        let densities = [density(region, &[(Vec3::ONE * 400., 100), (Vec3::ONE * 100., 50)])];
+        root.restructure_allocate(world, &mut free_servers, &constraints, &densities, &mut events);
+        assert!(octant(&mut root, 1, 1, 1).0.is_octree());
+
+        // Once it empties out server 1 takes the whole block back from server 0, which still has the rest of the world
+        set_populations(&mut root, 10);
+        events.clear();
+        root.restructure_free(world, &constraints, &mut events);
+        assert!(events.iter().any(|e| matches!(e, EventKind::Merge { merged_index: 0, .. })));
+        assert_used_servers_not_free(&root, &free_servers);
+    }
+}
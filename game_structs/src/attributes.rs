@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+pub static NAME: &str = "name"; // Text shown for the player
+pub static COLOR: &str = "color"; // Colour the player is drawn in
+pub static HEALTH: &str = "health";
+pub static TEAM: &str = "team";
+
+/// Attributes players can set for themselves, anything else can only be changed by services
+pub static CLIENT_ATTRIBUTES: [&str; 2] = [NAME, COLOR];
+pub static MAX_TEXT_LENGTH: usize = 32; // Longest text players can set an attribute to, in characters
+
+/// The value of a player attribute
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Text(String),
+    /// Red, green and blue, from 0 to 1
+    Color([f32; 3]),
+}
+
+/// Attributes by name, see the statics in this module for the well known ones
+pub type Attributes = BTreeMap<String, AttributeValue>;
+
+/// Apply changed attributes, returning the ones that were actually different
+pub fn merge(attributes: &mut Attributes, changes: Attributes) -> Attributes {
+    let mut changed = Attributes::new();
+    for (name, value) in changes {
+        if attributes.get(&name) != Some(&value) {
+            attributes.insert(name.clone(), value.clone());
+            changed.insert(name, value);
+        }
+    }
+    changed
+}
+
+/// Whether players are allowed to set this attribute themselves
+pub fn client_settable(name: &str) -> bool {
+    CLIENT_ATTRIBUTES.contains(&name)
+}
+
+/// Whether a value is one players are allowed to set, everyone is sent them so they have to stay small
+pub fn client_valid(value: &AttributeValue) -> bool {
+    match value {
+        AttributeValue::Text(text) => text.chars().count() <= MAX_TEXT_LENGTH,
+        AttributeValue::Float(f) => f.is_finite(),
+        AttributeValue::Color(color) => color.iter().all(|c| c.is_finite()),
+        AttributeValue::Bool(_) | AttributeValue::Int(_) => true,
+    }
+}
@@ -0,0 +1,123 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Header requests carry their token in
+pub const AUTH_HEADER: &str = "X-Auth-Token";
+
+/// Secret shared between the coordination server and every server
+#[derive(Clone, Debug)]
+pub struct AuthSecret(pub String);
+
+impl AuthSecret {
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(self.0.as_bytes()).expect("HMAC accepts any key length")
+    }
+
+    /// Sign a player ID, giving a token that lets the player act as themselves on any server
+    pub fn sign_player(&self, player_id: Uuid) -> String {
+        let mut mac = self.mac();
+        mac.update(player_id.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Check a token was signed for this player
+    pub fn verify_player(&self, player_id: Uuid, token: &str) -> bool {
+        let token = match hex::decode(token) {
+            Ok(t) => t,
+            Err(_) => return false,
+        };
+        let mut mac = self.mac();
+        mac.update(player_id.as_bytes());
+        mac.verify_slice(&token).is_ok()
+    }
+
+    /// Sign a message between services, so it can be sent somewhere the secret itself can't go
+    pub fn sign(&self, message: &[u8]) -> String {
+        let mut mac = self.mac();
+        mac.update(message);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Check a message was signed with the shared secret
+    pub fn verify(&self, message: &[u8], signature: &str) -> bool {
+        let signature = match hex::decode(signature) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// Check a token is the shared secret itself, which is only handed to services and admins
+    pub fn verify_service(&self, token: &str) -> bool {
+        // Compare MACs of both so the comparison is constant time
+        let mut mac = self.mac();
+        mac.update(token.as_bytes());
+        let mut expected = self.mac();
+        expected.update(self.0.as_bytes());
+        mac.verify_slice(&expected.finalize().into_bytes()).is_ok()
+    }
+}
+
+#[cfg(feature = "rocket")]
+pub use guards::*;
+
+#[cfg(feature = "rocket")]
+mod guards {
+    use rocket::{http::Status, request::{FromRequest, Outcome, Request}};
+    use uuid::Uuid;
+
+    use super::{AuthSecret, AUTH_HEADER};
+
+    /// Whatever token the request carried, to be checked against the body by the endpoint
+    pub struct AuthToken {
+        token: Option<String>,
+        secret: AuthSecret,
+    }
+
+    impl AuthToken {
+        /// The request came from another service or an admin
+        pub fn is_service(&self) -> bool {
+            self.token.as_ref().is_some_and(|t| self.secret.verify_service(t))
+        }
+
+        /// The request came from this player, or from a service acting for them
+        pub fn is_player(&self, player_id: Uuid) -> bool {
+            self.is_service() || self.token.as_ref().is_some_and(|t| self.secret.verify_player(player_id, t))
+        }
+    }
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for AuthToken {
+        type Error = ();
+
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            match request.rocket().state::<AuthSecret>() {
+                Some(secret) => Outcome::Success(AuthToken {
+                    token: request.headers().get_one(AUTH_HEADER).map(|t| t.to_string()),
+                    secret: secret.clone(),
+                }),
+                None => Outcome::Failure((Status::InternalServerError, ())), // Secret was never managed
+            }
+        }
+    }
+
+    /// Only lets through requests from other services and admins
+    pub struct ServiceAuth;
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for ServiceAuth {
+        type Error = ();
+
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            match AuthToken::from_request(request).await {
+                Outcome::Success(token) if token.is_service() => Outcome::Success(ServiceAuth),
+                Outcome::Success(_) => Outcome::Failure((Status::Unauthorized, ())),
+                Outcome::Failure(f) => Outcome::Failure(f),
+                Outcome::Forward(f) => Outcome::Forward(f),
+            }
+        }
+    }
+}
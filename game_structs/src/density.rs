@@ -0,0 +1,83 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{Aabb, Vec3};
+
+/// Number of cells along each axis of a density histogram
+pub static DENSITY_RESOLUTION: usize = 8;
+
+/// Coarse count of players in a grid of cells covering a region
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DensityHistogram {
+    pub region: Aabb,
+    pub resolution: usize,
+    /// Player count of each cell, indexed by x * resolution^2 + y * resolution + z
+    pub counts: Vec<usize>,
+}
+
+impl DensityHistogram {
+    /// Count the positions inside the region, anything outside it is ignored
+    pub fn new(region: Aabb, resolution: usize, positions: impl IntoIterator<Item = Vec3>) -> Self {
+        let mut counts = vec![0; resolution * resolution * resolution];
+        let size = region.size();
+        for position in positions {
+            if !region.contains(position) {continue;}
+            let cell = ((position - region.min) / size * resolution as f32).floor();
+            let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+            counts[(x.min(resolution - 1) * resolution + y.min(resolution - 1)) * resolution + z.min(resolution - 1)] += 1;
+        }
+        Self { region, resolution, counts }
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Players counted along one axis (0 = x, 1 = y, 2 = z), summed over the other two
+    fn marginal(&self, axis: usize) -> Vec<usize> {
+        let res = self.resolution;
+        let mut marginal = vec![0; res];
+        for (i, count) in self.counts.iter().enumerate() {
+            let cell = [i / (res * res), i / res % res, i % res];
+            marginal[cell[axis]] += count;
+        }
+        marginal
+    }
+
+    /// Pick the cell boundary on each axis that splits the players most evenly.
+    /// Returns the split point and the number of cells below it on each axis
+    pub fn split_point(&self) -> (Vec3, [usize; 3]) {
+        let res = self.resolution;
+        let mut cells = [res / 2; 3];
+        for (axis, cell) in cells.iter_mut().enumerate() {
+            let marginal = self.marginal(axis);
+            let total: usize = marginal.iter().sum();
+            if total == 0 {continue;} // Nothing to go by, keep the middle
+            let mut below = 0;
+            let mut best = (usize::MAX, res / 2);
+            for boundary in 1..res {
+                below += marginal[boundary - 1];
+                let imbalance = (2 * below).abs_diff(total);
+                if imbalance < best.0 {
+                    best = (imbalance, boundary);
+                }
+            }
+            *cell = best.1;
+        }
+        let size = self.region.size();
+        let fraction = Vec3::new(cells[0] as f32, cells[1] as f32, cells[2] as f32) / res as f32;
+        (self.region.min + size * fraction, cells)
+    }
+
+    /// Number of players that would end up in each octant if split at these cell boundaries,
+    /// in the same order the octree stores its children
+    pub fn octant_counts(&self, cells: [usize; 3]) -> [usize; 8] {
+        let res = self.resolution;
+        let mut out = [0; 8];
+        for (i, count) in self.counts.iter().enumerate() {
+            let (x, y, z) = (i / (res * res), i / res % res, i % res);
+            let octant = (((x >= cells[0]) as usize * 2 + (y >= cells[1]) as usize) * 2) + (z >= cells[2]) as usize;
+            out[octant] += count;
+        }
+        out
+    }
+}
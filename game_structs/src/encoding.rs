@@ -0,0 +1,423 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{Aabb, Vec3, operations::PositionUpdate, sequence::is_newer};
+
+static STATES_KEPT: usize = 32; // How many sent snapshots to keep around as possible baselines
+static MAX_VIEW_SIZE: usize = 1024; // Start the view again from scratch once it tracks this many players
+pub static COMPACT_BATCH_SIZE: usize = 28; // Entries per compact snapshot datagram, 40 bytes for a new player keeps them under a typical 1200 byte MTU
+
+/// How position updates are put on the wire, agreed between client and server when the player registers
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Full IDs and positions with bincode
+    Bincode,
+    /// Quantised positions, delta encoded against the last snapshot the client acknowledged
+    Compact,
+}
+
+/// Maps positions inside a region to 16 bit integers on each axis
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Quantizer {
+    pub region: Aabb,
+}
+
+impl Quantizer {
+    pub fn quantize(&self, position: Vec3) -> [u16; 3] {
+        let scaled = ((position - self.region.min) / self.region.size() * u16::MAX as f32)
+            .clamp(Vec3::ZERO, Vec3::ONE * u16::MAX as f32)
+            .round();
+        [scaled.x as u16, scaled.y as u16, scaled.z as u16]
+    }
+
+    pub fn dequantize(&self, position: [u16; 3]) -> Vec3 {
+        let scaled = Vec3::new(position[0] as f32, position[1] as f32, position[2] as f32) / u16::MAX as f32;
+        self.region.min + scaled * self.region.size()
+    }
+}
+
+/// A change to one slot of the snapshot, slots number players in the order they were first sent.
+/// Each carries the sequence number of the player's update it came from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CompactEntry {
+    /// A player the baseline doesn't have yet
+    Added { slot: u16, player_id: Uuid, position: [u16; 3], seq: u32 },
+    /// A player who moved a short way since the baseline
+    Moved { slot: u16, delta: [i16; 3], seq: u32 },
+    /// A player who moved too far since the baseline to send as a delta
+    Placed { slot: u16, position: [u16; 3], seq: u32 },
+}
+
+/// Part of a snapshot sent with the compact encoding, holding only what changed since the baseline
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactSnapshot {
+    /// The recipient's session key, so they know which server sent it
+    pub session_key: u64,
+    pub seq: u32,
+    /// When the server sent the snapshot, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// The acknowledged snapshot these changes apply to, None if they apply to an empty one
+    pub baseline: Option<u32>,
+    pub part: u8,
+    pub parts: u8,
+    pub entries: Vec<CompactEntry>,
+}
+
+/// A position update sent with the compact encoding, the server knows who it is from by the session key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactUpdate {
+    pub session_key: u64,
+    pub position: [u16; 3],
+    pub seq: u32,
+    pub timestamp: u64,
+    /// Latest snapshot from this server the client has every part of
+    pub ack: Option<u32>,
+}
+
+/// Server side of a compact stream to one client, tracks what the client has been sent
+#[derive(Debug, Clone, Default)]
+pub struct CompactEncoder {
+    /// Latest position and sequence number of every player the client has heard about, in slot order
+    view: Vec<(Uuid, [u16; 3], u32)>,
+    slots: HashMap<Uuid, usize>,
+    /// Positions in the view at each recently sent snapshot
+    sent: VecDeque<(u32, Vec<[u16; 3]>)>,
+    seq: u32,
+    changed: bool,
+}
+
+impl CompactEncoder {
+    /// Note the latest position of a player, and the sequence number of the update it came from
+    pub fn update(&mut self, player_id: Uuid, position: [u16; 3], seq: u32) {
+        if self.view.len() >= MAX_VIEW_SIZE && !self.slots.contains_key(&player_id) {
+            *self = Self { seq: self.seq, ..Default::default() }; // Slots can't be removed, so start again
+        }
+        match self.slots.get(&player_id) {
+            Some(slot) => self.view[*slot] = (player_id, position, seq),
+            None => {
+                self.slots.insert(player_id, self.view.len());
+                self.view.push((player_id, position, seq));
+            }
+        }
+        self.changed = true;
+    }
+
+    /// Build the next snapshot against the latest one the client acknowledged.
+    /// Returns nothing if the client already has everything
+    pub fn encode(&mut self, session_key: u64, ack: Option<u32>, timestamp: u64) -> Vec<CompactSnapshot> {
+        let baseline = ack.and_then(|ack| self.sent.iter().find(|(seq, _)| *seq == ack));
+        let base: &[[u16; 3]] = baseline.map_or(&[], |(_, positions)| positions);
+        let mut entries = vec![];
+        for (slot, (player_id, position, seq)) in self.view.iter().enumerate() {
+            let slot_id = slot as u16;
+            match base.get(slot) {
+                Some(old) if old == position => {},
+                Some(old) => {
+                    let delta = [0, 1, 2].map(|i| position[i] as i32 - old[i] as i32);
+                    if delta.iter().all(|d| *d >= i16::MIN as i32 && *d <= i16::MAX as i32) {
+                        entries.push(CompactEntry::Moved { slot: slot_id, delta: delta.map(|d| d as i16), seq: *seq });
+                    } else {
+                        entries.push(CompactEntry::Placed { slot: slot_id, position: *position, seq: *seq });
+                    }
+                },
+                None => entries.push(CompactEntry::Added { slot: slot_id, player_id: *player_id, position: *position, seq: *seq }),
+            }
+        }
+        if entries.is_empty() && !self.changed {return vec![];}
+        let baseline = baseline.map(|(seq, _)| *seq);
+
+        self.seq = self.seq.wrapping_add(1);
+        self.changed = false;
+        self.sent.push_back((self.seq, self.view.iter().map(|(_, p, _)| *p).collect()));
+        if self.sent.len() > STATES_KEPT {
+            self.sent.pop_front();
+        }
+
+        let mut chunks: Vec<Vec<CompactEntry>> = entries.chunks(COMPACT_BATCH_SIZE).map(|c| c.to_vec()).collect();
+        if chunks.is_empty() {
+            chunks.push(vec![]); // Still send an empty snapshot so the client can acknowledge it
+        }
+        let parts = chunks.len();
+        chunks.into_iter().enumerate().map(|(part, entries)| CompactSnapshot {
+            session_key,
+            seq: self.seq,
+            timestamp,
+            baseline,
+            part: part as u8,
+            parts: parts as u8,
+            entries,
+        }).collect()
+    }
+}
+
+/// Player and position in each slot of a snapshot, as rebuilt by the client
+type SlotState = Vec<Option<(Uuid, [u16; 3])>>;
+
+/// Client side of a compact stream from one server, rebuilds snapshots and works out what to acknowledge
+#[derive(Debug, Clone)]
+pub struct CompactDecoder {
+    pub quantizer: Quantizer,
+    /// Completed snapshots, which later ones can be built on
+    states: VecDeque<(u32, SlotState)>,
+    /// Snapshots still waiting on some parts, with the parts received so far
+    partial: HashMap<u32, (Vec<bool>, SlotState)>,
+    pub ack: Option<u32>,
+}
+
+impl CompactDecoder {
+    pub fn new(quantizer: Quantizer) -> Self {
+        Self { quantizer, states: VecDeque::new(), partial: HashMap::new(), ack: None }
+    }
+
+    /// Apply one part of a snapshot, returning the updates it carries.
+    /// Parts built on a baseline we no longer have are dropped
+    pub fn decode(&mut self, snapshot: &CompactSnapshot) -> Vec<PositionUpdate> {
+        if !self.partial.contains_key(&snapshot.seq) {
+            if self.states.iter().any(|(seq, _)| *seq == snapshot.seq) {return vec![];} // Already have it all
+            let base = match snapshot.baseline {
+                Some(baseline) => match self.states.iter().find(|(seq, _)| *seq == baseline) {
+                    Some((_, state)) => state.clone(),
+                    None => return vec![],
+                },
+                None => vec![],
+            };
+            self.partial.insert(snapshot.seq, (vec![false; snapshot.parts as usize], base));
+        }
+        let (received, state) = self.partial.get_mut(&snapshot.seq).unwrap();
+        if received.get(snapshot.part as usize) != Some(&false) {return vec![];}
+        received[snapshot.part as usize] = true;
+
+        let mut updates = vec![];
+        for entry in &snapshot.entries {
+            let (slot, player_id, position, seq) = match entry {
+                CompactEntry::Added { slot, player_id, position, seq } => (*slot as usize, Some(*player_id), *position, *seq),
+                CompactEntry::Moved { slot, delta, seq } => match state.get(*slot as usize).copied().flatten() {
+                    Some((_, old)) => (*slot as usize, None, [0, 1, 2].map(|i| (old[i] as i32 + delta[i] as i32) as u16), *seq),
+                    None => continue,
+                },
+                CompactEntry::Placed { slot, position, seq } => (*slot as usize, None, *position, *seq),
+            };
+            if state.len() <= slot {
+                state.resize(slot + 1, None);
+            }
+            let player_id = match player_id.or_else(|| state[slot].map(|(id, _)| id)) {
+                Some(id) => id,
+                None => continue,
+            };
+            state[slot] = Some((player_id, position));
+            // Entries only carry when the server sent them, not when the player did
+            updates.push(PositionUpdate { player_id, position: self.quantizer.dequantize(position), seq, timestamp: snapshot.timestamp });
+        }
+
+        // Once every part is in, the snapshot can be acknowledged and built on
+        if received.iter().all(|r| *r) {
+            let (_, state) = self.partial.remove(&snapshot.seq).unwrap();
+            self.states.push_back((snapshot.seq, state));
+            if self.states.len() > STATES_KEPT {
+                self.states.pop_front();
+            }
+            self.partial.retain(|seq, _| is_newer(*seq, snapshot.seq)); // Older snapshots won't be needed
+            if self.ack.is_none_or(|ack| is_newer(snapshot.seq, ack)) {
+                self.ack = Some(snapshot.seq);
+            }
+        }
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantizer() -> Quantizer {
+        Quantizer { region: Aabb::new(Vec3::ONE * -512., Vec3::ONE * 512.) }
+    }
+
+    fn players(n: usize) -> Vec<Uuid> {
+        (0..n).map(|_| Uuid::new_v4()).collect()
+    }
+
+    /// Where the client should end up seeing a position after it has been quantised
+    fn expected(position: Vec3) -> Vec3 {
+        quantizer().dequantize(quantizer().quantize(position))
+    }
+
+    fn decode_all(decoder: &mut CompactDecoder, snapshots: &[CompactSnapshot]) -> HashMap<Uuid, Vec3> {
+        snapshots.iter().flat_map(|s| decoder.decode(s)).map(|u| (u.player_id, u.position)).collect()
+    }
+
+    /// Encode against what the decoder has acknowledged and deliver every part
+    fn send(encoder: &mut CompactEncoder, decoder: &mut CompactDecoder) -> HashMap<Uuid, Vec3> {
+        let snapshots = encoder.encode(7, decoder.ack, 0);
+        decode_all(decoder, &snapshots)
+    }
+
+    #[test]
+    fn round_trip_sends_only_changes() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        let ids = players(3);
+        for (i, id) in ids.iter().enumerate() {
+            encoder.update(*id, quantizer().quantize(Vec3::new(i as f32, 0., 0.)), 1);
+        }
+        let first = encoder.encode(7, decoder.ack, 0);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].baseline, None);
+        let updates = decode_all(&mut decoder, &first);
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[&ids[2]], expected(Vec3::new(2., 0., 0.)));
+        assert_eq!(decoder.ack, Some(first[0].seq));
+
+        // Only the player who moved is sent, as a delta on the acknowledged snapshot
+        encoder.update(ids[1], quantizer().quantize(Vec3::new(1.5, 0., 0.)), 2);
+        let second = encoder.encode(7, decoder.ack, 0);
+        assert_eq!(second[0].baseline, decoder.ack);
+        assert!(matches!(second[0].entries.as_slice(), [CompactEntry::Moved { slot: 1, seq: 2, .. }]));
+        let updates = decode_all(&mut decoder, &second);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[&ids[1]], expected(Vec3::new(1.5, 0., 0.)));
+    }
+
+    #[test]
+    fn nothing_is_sent_once_the_client_has_everything() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        encoder.update(Uuid::new_v4(), [1, 2, 3], 1);
+        send(&mut encoder, &mut decoder);
+        assert!(encoder.encode(7, decoder.ack, 0).is_empty());
+    }
+
+    #[test]
+    fn far_moves_are_placed_rather_than_delta_encoded() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        let id = Uuid::new_v4();
+        encoder.update(id, quantizer().quantize(Vec3::ONE * -500.), 1);
+        send(&mut encoder, &mut decoder);
+        encoder.update(id, quantizer().quantize(Vec3::ONE * 500.), 2);
+        let snapshots = encoder.encode(7, decoder.ack, 0);
+        assert!(matches!(snapshots[0].entries.as_slice(), [CompactEntry::Placed { slot: 0, .. }]));
+        assert_eq!(decode_all(&mut decoder, &snapshots)[&id], expected(Vec3::ONE * 500.));
+    }
+
+    #[test]
+    fn lost_snapshots_are_covered_by_the_next_one() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        let id = Uuid::new_v4();
+        encoder.update(id, quantizer().quantize(Vec3::ZERO), 1);
+        send(&mut encoder, &mut decoder);
+        let acked = decoder.ack;
+
+        // Lost on the way, so the client keeps acknowledging the first one
+        encoder.update(id, quantizer().quantize(Vec3::X), 2);
+        let _lost = encoder.encode(7, decoder.ack, 0);
+        encoder.update(id, quantizer().quantize(Vec3::X * 2.), 3);
+        let next = encoder.encode(7, decoder.ack, 0);
+        assert_eq!(next[0].baseline, acked);
+        let updates: Vec<PositionUpdate> = next.iter().flat_map(|s| decoder.decode(s)).collect();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].position, expected(Vec3::X * 2.));
+        assert_eq!(updates[0].seq, 3);
+        assert_eq!(decoder.ack, Some(next[0].seq));
+    }
+
+    #[test]
+    fn parts_are_reassembled_in_any_order() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        let ids = players(COMPACT_BATCH_SIZE + 5);
+        for (i, id) in ids.iter().enumerate() {
+            encoder.update(*id, quantizer().quantize(Vec3::new(i as f32, 1., 2.)), 1);
+        }
+        let snapshots = encoder.encode(7, decoder.ack, 0);
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.iter().all(|s| s.parts == 2));
+
+        // Not acknowledged until every part is in, and repeated parts are ignored
+        let mut updates = decoder.decode(&snapshots[1]);
+        assert_eq!(decoder.ack, None);
+        assert!(decoder.decode(&snapshots[1]).is_empty());
+        updates.extend(decoder.decode(&snapshots[0]));
+        assert_eq!(decoder.ack, Some(snapshots[0].seq));
+        assert_eq!(updates.len(), ids.len());
+        assert!(decoder.decode(&snapshots[0]).is_empty());
+    }
+
+    #[test]
+    fn a_full_part_of_new_players_fits_in_a_datagram() {
+        let mut encoder = CompactEncoder::default();
+        for (i, id) in players(COMPACT_BATCH_SIZE).iter().enumerate() {
+            encoder.update(*id, quantizer().quantize(Vec3::new(i as f32, 1., 2.)), u32::MAX);
+        }
+        let snapshots = encoder.encode(u64::MAX, None, u64::MAX);
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots[0].entries.iter().all(|e| matches!(e, CompactEntry::Added { .. })));
+        let size = bincode::serialized_size(&crate::operations::ServerMessage::Compact(snapshots[0].clone())).unwrap();
+        assert!(size <= 1200, "{} bytes", size);
+    }
+
+    #[test]
+    fn late_snapshots_dont_move_the_ack_back() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        let id = Uuid::new_v4();
+        encoder.update(id, [1, 1, 1], 1);
+        let older = encoder.encode(7, None, 0);
+        encoder.update(id, [2, 2, 2], 2);
+        let newer = encoder.encode(7, None, 0);
+
+        decode_all(&mut decoder, &newer);
+        decode_all(&mut decoder, &older);
+        assert_eq!(decoder.ack, Some(newer[0].seq));
+    }
+
+    #[test]
+    fn snapshots_on_a_missing_baseline_are_dropped() {
+        let mut decoder = CompactDecoder::new(quantizer());
+        let snapshot = CompactSnapshot {
+            session_key: 7,
+            seq: 5,
+            timestamp: 0,
+            baseline: Some(4),
+            part: 0,
+            parts: 1,
+            entries: vec![CompactEntry::Placed { slot: 0, position: [1, 2, 3], seq: 1 }],
+        };
+        assert!(decoder.decode(&snapshot).is_empty());
+        assert_eq!(decoder.ack, None);
+    }
+
+    #[test]
+    fn full_view_starts_again() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        for id in players(MAX_VIEW_SIZE) {
+            encoder.update(id, [1, 1, 1], 1);
+        }
+        send(&mut encoder, &mut decoder);
+        let seq = decoder.ack.unwrap();
+
+        // One more player doesn't fit, so the view and the baselines are thrown away
+        let newcomer = Uuid::new_v4();
+        encoder.update(newcomer, [2, 2, 2], 1);
+        let snapshots = encoder.encode(7, decoder.ack, 0);
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].baseline, None);
+        assert_eq!(snapshots[0].seq, seq.wrapping_add(1));
+        assert!(matches!(snapshots[0].entries.as_slice(), [CompactEntry::Added { slot: 0, player_id, .. }] if *player_id == newcomer));
+        let updates = decode_all(&mut decoder, &snapshots);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(decoder.ack, Some(snapshots[0].seq));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let (mut encoder, mut decoder) = (CompactEncoder::default(), CompactDecoder::new(quantizer()));
+        encoder.seq = u32::MAX - 1;
+        let id = Uuid::new_v4();
+        for step in 0..4u16 {
+            encoder.update(id, [step, 0, 0], step as u32);
+            let snapshots = encoder.encode(7, decoder.ack, 0);
+            let updates = decode_all(&mut decoder, &snapshots);
+            assert_eq!(updates[&id], quantizer().dequantize([step, 0, 0]));
+            assert_eq!(decoder.ack, Some(snapshots[0].seq));
+        }
+        assert_eq!(decoder.ack, Some(2));
+    }
+}
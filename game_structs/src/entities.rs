@@ -0,0 +1,72 @@
+use serde::{Serialize, Deserialize};
+use bevy::prelude::*;
+use uuid::Uuid;
+
+use crate::attributes::Attributes;
+
+/// What sort of thing an entity is, which decides how clients draw it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum EntityKind {
+    Npc,
+    Projectile,
+    Item,
+    /// Anything else, named by the game
+    Other(String),
+}
+
+/// Something other than a player that servers keep in sync with clients, such as an NPC or a dropped item
+#[derive(Serialize, Deserialize, Clone, Debug, Component)]
+pub struct ReplicatedEntity {
+    pub id: Uuid,
+    pub kind: EntityKind,
+    /// The player the entity belongs to, None if it belongs to the server
+    pub owner: Option<Uuid>,
+    pub position: Vec3,
+    pub rotation: Quat,
+    /// Whatever else the game needs to know about it
+    pub data: Attributes,
+}
+
+/// Where an entity has moved to, sent unreliably every tick it moves
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EntityUpdate {
+    pub id: Uuid,
+    /// Goes up by one every time the entity moves, so older updates that arrive late can be dropped
+    pub seq: u32,
+    pub position: Vec3,
+    pub rotation: Quat,
+}
+
+/// Sent to a server to create an entity. Players can only create entities they own
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpawnEntity {
+    pub kind: EntityKind,
+    pub owner: Option<Uuid>,
+    pub position: Vec3,
+    pub rotation: Quat,
+    #[serde(default)]
+    pub data: Attributes,
+}
+
+/// Sent to a server to move an entity or change its data, by its owner or a service
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangeEntity {
+    pub id: Uuid,
+    pub position: Option<Vec3>,
+    pub rotation: Option<Quat>,
+    #[serde(default)]
+    pub data: Attributes,
+}
+
+impl ReplicatedEntity {
+    pub fn new(spawn: SpawnEntity) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            kind: spawn.kind,
+            owner: spawn.owner,
+            position: spawn.position,
+            rotation: spawn.rotation,
+            data: spawn.data,
+        }
+    }
+}
@@ -1,11 +1,84 @@
+pub mod attributes;
+pub mod auth;
+pub mod density;
+pub mod encoding;
+pub mod entities;
+pub mod metrics;
 pub mod operations;
+pub mod reliable;
+pub mod routing;
+pub mod sequence;
 
 use serde::{Serialize, Deserialize};
 use bevy::prelude::*;
-pub use bevy::prelude::Vec3;
+pub use bevy::prelude::{Vec3, Quat};
 use uuid::Uuid;
+use attributes::Attributes;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Component)]
 pub struct Player {
     pub id: Uuid,
-}
\ No newline at end of file
+    /// Typed data about the player, such as their name or health
+    #[serde(default)]
+    pub attributes: Attributes,
+}
+
+impl Player {
+    pub fn new(id: Uuid) -> Self {
+        Self { id, attributes: Attributes::new() }
+    }
+
+    /// Apply changed attributes, returning the ones that were actually different
+    pub fn set_attributes(&mut self, changes: Attributes) -> Attributes {
+        attributes::merge(&mut self.attributes, changes)
+    }
+}
+
+/// Where a server can be reached, both for REST requests and UDP position updates
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub http: String,
+    pub udp: String,
+}
+
+/// An axis aligned box, used to describe the region of the world a server covers
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.cmpge(self.min).all() && point.cmplt(self.max).all()
+    }
+
+    /// Whether the two boxes overlap at all
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.cmplt(other.max).all() && other.min.cmplt(self.max).all()
+    }
+
+    /// Whether the other box is completely inside this one
+    pub fn contains_aabb(&self, other: &Aabb) -> bool {
+        self.min.cmple(other.min).all() && other.max.cmple(self.max).all()
+    }
+
+    /// One of the eight boxes made by cutting this box along every axis at the split point
+    pub fn octant(&self, split: Vec3, x: usize, y: usize, z: usize) -> Aabb {
+        let pick = |i: usize, min: f32, split: f32, max: f32| if i == 0 {(min, split)} else {(split, max)};
+        let (x, y, z) = (pick(x, self.min.x, split.x, self.max.x), pick(y, self.min.y, split.y, self.max.y), pick(z, self.min.z, split.z, self.max.z));
+        Aabb::new(Vec3::new(x.0, y.0, z.0), Vec3::new(x.1, y.1, z.1))
+    }
+}
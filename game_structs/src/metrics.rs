@@ -0,0 +1,18 @@
+use std::fmt::{Display, Write};
+
+/// Kind of a Prometheus metric, used for the TYPE line
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+/// Append a single metric in the Prometheus text exposition format
+pub fn write_metric(out: &mut String, name: &str, help: &str, kind: MetricKind, value: impl Display) {
+    let kind = match kind {
+        MetricKind::Counter => "counter",
+        MetricKind::Gauge => "gauge",
+    };
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
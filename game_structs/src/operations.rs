@@ -1,16 +1,189 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Serialize, Deserialize};
 use bevy::prelude::*;
 
-use crate::Player;
+use crate::{Player, attributes::Attributes, entities::{EntityUpdate, ReplicatedEntity}, encoding::{CompactSnapshot, CompactUpdate, Encoding, Quantizer}, reliable::ReliableMessage};
 
-#[derive(Serialize, Deserialize)]
+pub static EVENT_CHANNEL: u8 = 0; // Reliable channel for joins and leaves, in order
+pub static ACTION_CHANNEL: u8 = 1; // Reliable channel for player actions, in any order
+pub static CHAT_CHANNEL: u8 = 2; // Reliable channel for chat, in order
+pub static MAX_CHAT_LENGTH: usize = 256; // Longest chat message servers pass on, in characters
+pub static MAX_ACTION_LENGTH: usize = 64; // Longest action servers pass on, in bytes, longer ones are dropped
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PositionUpdate {
     pub player_id: uuid::Uuid,
-    pub position: Vec3
+    pub position: Vec3,
+    /// Goes up by one with every update the player sends, so older updates that arrive late can be dropped
+    pub seq: u32,
+    /// When the update was sent, in milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// A position update sent from a client to a server, along with the key the server gave the player when they registered
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientUpdate {
+    pub session_key: u64,
+    pub update: PositionUpdate
+}
+
+/// Everything a client, or a neighbouring server, sends to a server over UDP
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ClientMessage {
+    Update(ClientUpdate),
+    Compact(CompactUpdate),
+    /// Part of the reliable connection to the server the session key was given by
+    Reliable { session_key: u64, message: ReliableMessage },
+    Peer(PeerUpdate),
+}
+
+/// Updates from players near the border of a neighbouring server, so players on this side can see them
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeerUpdate {
+    /// Index of the server the players are on
+    pub server: usize,
+    /// When the sending server started, in milliseconds since the unix epoch
+    pub started: u64,
+    /// Tick of the sending server the updates are from, so old messages can't be replayed
+    pub tick: u64,
+    pub updates: Vec<PositionUpdate>,
+    /// Attributes of players near the border, sent every so often rather than with every update
+    pub attributes: Vec<(uuid::Uuid, Attributes)>,
+    /// HMAC of everything else with the shared secret, proving the updates came from a server
+    pub signature: String,
+}
+
+/// Gameplay events that have to arrive, sent over the reliable channels
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum GameEvent {
+    PlayerJoined { player_id: uuid::Uuid },
+    PlayerLeft { player_id: uuid::Uuid },
+    /// Something a player did, servers pass these on to nearby players
+    Action { player_id: uuid::Uuid, action: String },
+    /// Some of a player's attributes changed, carrying just the new values
+    AttributesChanged { player_id: uuid::Uuid, attributes: Attributes },
+    EntitySpawned(ReplicatedEntity),
+    /// Some of an entity's data changed, carrying just the new values
+    EntityChanged { id: uuid::Uuid, data: Attributes },
+    EntityDespawned { id: uuid::Uuid },
+    /// Something a player said, servers pass it on to everyone in scope
+    Chat { player_id: uuid::Uuid, scope: ChatScope, text: String },
+}
+
+/// Who a chat message reaches
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+    /// Players close to the speaker
+    Proximity,
+    /// Players on the same server as the speaker
+    Region,
+    /// Every player in the world, passed between servers by the coord
+    Global,
+}
+
+/// A global chat message on its way through the coord to every server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GlobalChat {
+    /// Given by the coord, goes up by one with every message
+    #[serde(default)]
+    pub id: u64,
+    /// HTTP address of the server the speaker is on, which has already passed it on to its own players
+    pub origin: String,
+    pub player_id: uuid::Uuid,
+    pub text: String,
+}
+
+/// Global chat handed out by the coord
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessages {
+    /// Changes when the coord restarts and IDs start again from 0
+    pub generation: u64,
+    pub messages: Vec<GlobalChat>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PlayerRegister {
     pub player: Player,
-    pub address: String
-}
\ No newline at end of file
+    pub address: String,
+    /// Encodings the client can use, most preferred first
+    pub encodings: Vec<Encoding>
+}
+
+/// Sent back once a player is registered, the token proves who they are to every server
+#[derive(Serialize, Deserialize)]
+pub struct PlayerRegistered {
+    pub player_id: uuid::Uuid,
+    pub token: String,
+    /// Must be sent with every update to this server, so other people can't send updates for this player
+    pub session_key: u64,
+    /// Encoding the server picked from the ones the client offered
+    pub encoding: Encoding,
+    /// Bounds used to quantise positions with the compact encoding
+    pub quantizer: Quantizer,
+    /// Where every other player on the server is right now, so the new player doesn't have to wait for their next updates
+    pub snapshot: Vec<PositionUpdate>
+}
+
+/// Everything a server sends to clients over UDP
+#[derive(Serialize, Deserialize)]
+pub enum ServerMessage {
+    PositionUpdate(PositionUpdate),
+    /// The latest updates from players near this one, batched up once per server tick
+    Snapshot(Vec<PositionUpdate>),
+    /// Part of a snapshot for players using the compact encoding
+    Compact(CompactSnapshot),
+    /// The world was restructured and the player should move to these servers now
+    Handoff { servers: HashSet<usize> },
+    /// Part of the reliable connection for the player with this session key
+    Reliable { session_key: u64, message: ReliableMessage },
+    /// Entities near this player that moved, batched up once per server tick
+    EntityUpdates(Vec<EntityUpdate>),
+    /// Where the server put this player after their last update broke a movement rule, they should move back there
+    Correction(PositionUpdate),
+}
+
+/// Sent by the coordination server to tell a server which of its players now belong elsewhere
+#[derive(Serialize, Deserialize)]
+pub struct HandoffRequest {
+    pub handoff_id: u64,
+    /// How long players have to leave before the server drops them
+    pub deadline_millis: u64,
+    /// The servers each departing player should move to
+    pub players: HashMap<uuid::Uuid, HashSet<usize>>
+}
+
+/// Sent by a server once a departing player has left, or was dropped at the deadline
+#[derive(Serialize, Deserialize)]
+pub struct HandoffComplete {
+    pub handoff_id: u64,
+    pub player_id: uuid::Uuid,
+    pub timed_out: bool
+}
+
+/// Sent to a server to change a player's attributes. Players can only set their own, and only the client settable ones
+#[derive(Serialize, Deserialize)]
+pub struct AttributeUpdate {
+    pub player_id: uuid::Uuid,
+    pub attributes: Attributes,
+}
+
+/// Sent by a client to the server it is leaving, asking for its state to be passed on to the server it is moving to
+#[derive(Serialize, Deserialize)]
+pub struct PlayerTransfer {
+    pub player_id: uuid::Uuid,
+    pub to: usize,
+}
+
+/// Queries sent to the coordination server's UDP query socket, encoded with bincode
+#[derive(Serialize, Deserialize)]
+pub enum CoordRequest {
+    /// Which server a player at this position should be on
+    GetServer { request_id: u32, position: Vec3 },
+}
+
+/// Replies from the coordination server's UDP query socket, carrying the ID of the request they answer
+#[derive(Serialize, Deserialize)]
+pub enum CoordResponse {
+    Servers { request_id: u32, servers: HashSet<usize> },
+}
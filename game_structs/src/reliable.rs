@@ -0,0 +1,310 @@
+use std::{collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+static RESEND_INTERVAL: Duration = Duration::from_millis(200); // How long to wait for an ack before sending a packet again
+static MAX_SENDS: u32 = 25; // Times a packet is sent without being acked before the peer is given up on, 5 seconds of resends
+static MAX_UNACKED: usize = 1024; // Packets one channel can have waiting for acks before the peer is given up on
+static RECEIVE_WINDOW: u32 = 256; // How far past the next expected packet we accept packets, so a peer can't make us buffer without limit
+pub static MAX_PAYLOAD_SIZE: usize = 1100; // Largest payload that fits in one datagram with its headers, under a typical 1200 byte MTU. Enough for the longest chat message
+
+/// Whether messages on a channel have to be handed over in the order they were sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Ordered,
+    Unordered,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReliablePacket {
+    pub channel: u8,
+    pub seq: u32,
+    pub ordered: bool,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReliableAck {
+    pub channel: u8,
+    pub seq: u32,
+}
+
+/// Everything the reliability layer puts on the wire
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ReliableMessage {
+    Packet(ReliablePacket),
+    Ack(ReliableAck),
+}
+
+#[derive(Debug, Clone)]
+struct Outgoing {
+    packet: ReliablePacket,
+    last_sent: Option<Instant>,
+    sends: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ChannelState {
+    next_seq: u32,
+    unacked: BTreeMap<u32, Outgoing>,
+    /// Every packet below this has been received
+    next_expected: u32,
+    /// Packets received ahead of next_expected, with the payload if it is still waiting to be handed over in order
+    ahead: BTreeMap<u32, Option<Vec<u8>>>,
+}
+
+/// One end of a reliable connection to a peer, messages are resent until acked and handed over once each
+#[derive(Debug, Clone, Default)]
+pub struct ReliableEndpoint {
+    channels: HashMap<u8, ChannelState>,
+    acks: Vec<ReliableAck>,
+    failed: bool,
+    /// Packets that had to be sent again
+    pub resent: u64,
+}
+
+impl ReliableEndpoint {
+    /// Queue a message to go out on the next poll. Returns false, and drops the message, if it is too big to fit in one datagram
+    pub fn send(&mut self, channel: u8, delivery: Delivery, payload: Vec<u8>) -> bool {
+        if payload.len() > MAX_PAYLOAD_SIZE {return false;}
+        let state = self.channels.entry(channel).or_default();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.unacked.insert(seq, Outgoing {
+            packet: ReliablePacket { channel, seq, ordered: delivery == Delivery::Ordered, payload },
+            last_sent: None,
+            sends: 0,
+        });
+        if state.unacked.len() > MAX_UNACKED {
+            self.failed = true; // The peer isn't keeping up
+        }
+        true
+    }
+
+    /// Whether the peer has stopped acking, the connection should be dropped
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Handle a message from the peer, returning any payloads that are ready to be handed over
+    pub fn receive(&mut self, message: ReliableMessage) -> Vec<Vec<u8>> {
+        match message {
+            ReliableMessage::Ack(ack) => {
+                if let Some(state) = self.channels.get_mut(&ack.channel) {
+                    state.unacked.remove(&ack.seq);
+                }
+                vec![]
+            },
+            ReliableMessage::Packet(packet) => {
+                let state = self.channels.entry(packet.channel).or_default();
+                if packet.seq >= state.next_expected.saturating_add(RECEIVE_WINDOW) {
+                    return vec![]; // Too far ahead to hold on to, left unacked so it is sent again later
+                }
+                // Always ack, our last ack might have been lost
+                self.acks.push(ReliableAck { channel: packet.channel, seq: packet.seq });
+                if packet.seq < state.next_expected || state.ahead.contains_key(&packet.seq) {
+                    return vec![]; // Already have it
+                }
+
+                let mut delivered = vec![];
+                if packet.ordered {
+                    state.ahead.insert(packet.seq, Some(packet.payload));
+                } else {
+                    state.ahead.insert(packet.seq, None);
+                    delivered.push(packet.payload);
+                }
+                // Move past everything received in a row, handing over ordered payloads as they come up
+                while let Some(payload) = state.ahead.remove(&state.next_expected) {
+                    delivered.extend(payload);
+                    state.next_expected += 1;
+                }
+                delivered
+            }
+        }
+    }
+
+    /// Acks to send, and packets that are new or haven't been acked in time.
+    /// Gives up on the peer once a packet has been sent too many times without an ack
+    pub fn poll(&mut self, now: Instant) -> Vec<ReliableMessage> {
+        let mut out: Vec<ReliableMessage> = self.acks.drain(..).map(ReliableMessage::Ack).collect();
+        for state in self.channels.values_mut() {
+            for outgoing in state.unacked.values_mut() {
+                let due = match outgoing.last_sent {
+                    None => true,
+                    Some(t) if now.duration_since(t) >= RESEND_INTERVAL => {
+                        self.resent += 1;
+                        true
+                    },
+                    Some(_) => false,
+                };
+                if due && outgoing.sends >= MAX_SENDS {
+                    self.failed = true;
+                } else if due {
+                    outgoing.last_sent = Some(now);
+                    outgoing.sends += 1;
+                    out.push(ReliableMessage::Packet(outgoing.packet.clone()));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CHANNEL: u8 = 3;
+
+    fn packets(messages: Vec<ReliableMessage>) -> Vec<ReliableMessage> {
+        messages.into_iter().filter(|m| matches!(m, ReliableMessage::Packet(_))).collect()
+    }
+
+    fn acks(messages: &[ReliableMessage]) -> usize {
+        messages.iter().filter(|m| matches!(m, ReliableMessage::Ack(_))).count()
+    }
+
+    /// Hand messages to an endpoint, returning everything it handed over
+    fn deliver(to: &mut ReliableEndpoint, messages: impl IntoIterator<Item = ReliableMessage>) -> Vec<Vec<u8>> {
+        messages.into_iter().flat_map(|m| to.receive(m)).collect()
+    }
+
+    fn send_all(from: &mut ReliableEndpoint, delivery: Delivery, n: u8) {
+        for i in 0..n {
+            assert!(from.send(CHANNEL, delivery, vec![i]));
+        }
+    }
+
+    #[test]
+    fn ordered_messages_wait_for_the_ones_before() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        send_all(&mut a, Delivery::Ordered, 3);
+        let mut sent = packets(a.poll(Instant::now()));
+        sent.reverse();
+        let first = sent.remove(2);
+        assert!(deliver(&mut b, sent).is_empty());
+        assert_eq!(deliver(&mut b, [first]), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn unordered_messages_are_handed_over_as_they_arrive() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        send_all(&mut a, Delivery::Unordered, 3);
+        let mut sent = packets(a.poll(Instant::now()));
+        sent.reverse();
+        assert_eq!(deliver(&mut b, sent), vec![vec![2], vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        send_all(&mut a, Delivery::Unordered, 2);
+        let sent = packets(a.poll(Instant::now()));
+        let again = sent.clone();
+        assert_eq!(deliver(&mut b, sent).len(), 2);
+        assert!(deliver(&mut b, again).is_empty());
+        assert_eq!(acks(&b.poll(Instant::now())), 4); // Duplicates are still acked in case the first ack was lost
+    }
+
+    #[test]
+    fn dropped_packets_are_resent_until_acked() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        let start = Instant::now();
+        send_all(&mut a, Delivery::Ordered, 1);
+        assert_eq!(packets(a.poll(start)).len(), 1); // Dropped
+        assert!(a.poll(start + Duration::from_millis(100)).is_empty());
+        let resent = packets(a.poll(start + RESEND_INTERVAL));
+        assert_eq!(resent.len(), 1);
+        assert_eq!(a.resent, 1);
+
+        assert_eq!(deliver(&mut b, resent), vec![vec![0]]);
+        deliver(&mut a, b.poll(start + RESEND_INTERVAL));
+        assert!(a.poll(start + RESEND_INTERVAL * 2).is_empty());
+        assert!(!a.failed());
+    }
+
+    #[test]
+    fn lost_acks_are_sent_again() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        let start = Instant::now();
+        send_all(&mut a, Delivery::Ordered, 1);
+        assert_eq!(deliver(&mut b, packets(a.poll(start))), vec![vec![0]]);
+        assert_eq!(acks(&b.poll(start)), 1); // Dropped
+
+        assert!(deliver(&mut b, packets(a.poll(start + RESEND_INTERVAL))).is_empty());
+        deliver(&mut a, b.poll(start + RESEND_INTERVAL));
+        assert!(a.poll(start + RESEND_INTERVAL * 2).is_empty());
+    }
+
+    #[test]
+    fn ordered_messages_survive_drops_and_reordering() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        let start = Instant::now();
+        send_all(&mut a, Delivery::Ordered, 20);
+        let mut received = vec![];
+        for round in 0..4u32 {
+            let now = start + RESEND_INTERVAL * round;
+            // Drop every other packet the first time round and deliver the rest backwards
+            let mut sent: Vec<ReliableMessage> = packets(a.poll(now)).into_iter()
+                .enumerate()
+                .filter(|(i, _)| round > 0 || i % 2 == 1)
+                .map(|(_, m)| m)
+                .collect();
+            sent.reverse();
+            received.extend(deliver(&mut b, sent));
+            deliver(&mut a, b.poll(now));
+        }
+        assert_eq!(received, (0..20).map(|i| vec![i]).collect::<Vec<_>>());
+        assert!(a.poll(start + RESEND_INTERVAL * 5).is_empty());
+    }
+
+    #[test]
+    fn oversize_payloads_are_rejected() {
+        let mut a = ReliableEndpoint::default();
+        assert!(!a.send(CHANNEL, Delivery::Ordered, vec![0; MAX_PAYLOAD_SIZE + 1]));
+        assert!(a.poll(Instant::now()).is_empty());
+        assert!(a.send(CHANNEL, Delivery::Ordered, vec![0; MAX_PAYLOAD_SIZE]));
+    }
+
+    #[test]
+    fn packets_past_the_receive_window_are_left_unacked() {
+        let (mut a, mut b) = (ReliableEndpoint::default(), ReliableEndpoint::default());
+        let start = Instant::now();
+        for i in 0..=RECEIVE_WINDOW {
+            a.send(CHANNEL, Delivery::Ordered, i.to_be_bytes().to_vec());
+        }
+        let mut sent = packets(a.poll(start));
+        let first = sent.remove(0);
+        assert!(deliver(&mut b, sent).is_empty());
+        assert_eq!(acks(&b.poll(start)), RECEIVE_WINDOW as usize - 1);
+
+        assert_eq!(deliver(&mut b, [first]).len(), RECEIVE_WINDOW as usize);
+        // Only the one past the window is still waiting
+        let resent = packets(a.poll(start + RESEND_INTERVAL));
+        assert_eq!(deliver(&mut b, resent), vec![RECEIVE_WINDOW.to_be_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn peers_that_never_ack_are_given_up_on() {
+        let mut a = ReliableEndpoint::default();
+        let start = Instant::now();
+        send_all(&mut a, Delivery::Ordered, 1);
+        for i in 0..MAX_SENDS {
+            assert_eq!(packets(a.poll(start + RESEND_INTERVAL * i)).len(), 1);
+            assert!(!a.failed());
+        }
+        assert!(a.poll(start + RESEND_INTERVAL * MAX_SENDS).is_empty());
+        assert!(a.failed());
+    }
+
+    #[test]
+    fn too_many_unacked_messages_fail_the_endpoint() {
+        let mut a = ReliableEndpoint::default();
+        for _ in 0..MAX_UNACKED {
+            a.send(CHANNEL, Delivery::Unordered, vec![]);
+        }
+        assert!(!a.failed());
+        a.send(CHANNEL, Delivery::Unordered, vec![]);
+        assert!(a.failed());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Aabb, Vec3};
+
+/// A copy of the coordination server's region tree, enough to work out which servers cover a position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RegionNode {
+    /// Region cut at the split point, with the eight octants ordered by x, then y, then z
+    Split { split: Vec3, children: Vec<RegionNode> },
+    Leaf { index: usize },
+}
+
+impl RegionNode {
+    /// Get the servers covering this position, including neighbours within the border buffer
+    pub fn query(&self, position: Vec3, region: Aabb, border_buffer: f32) -> HashSet<usize> {
+        match self {
+            Self::Split { split, children } => {
+                let query = |x: usize, y: usize, z: usize| children[(x * 2 + y) * 2 + z].query(position, region.octant(*split, x, y, z), border_buffer);
+                let side = |p: f32, s: f32| if p < s {0} else {1};
+                let (x, y, z) = (side(position.x, split.x), side(position.y, split.y), side(position.z, split.z));
+                let mut main = query(x, y, z);
+                let half_size = region.size() / 2.;
+                if ((position.x - split.x) / half_size.x).abs() < border_buffer {
+                    // X crossover, get other chunk
+                    main.extend(query(1 - x, y, z));
+                }
+                if ((position.y - split.y) / half_size.y).abs() < border_buffer {
+                    // Y crossover, get other chunk
+                    main.extend(query(x, 1 - y, z));
+                }
+                if ((position.z - split.z) / half_size.z).abs() < border_buffer {
+                    // Z crossover, get other chunk
+                    main.extend(query(x, y, 1 - z));
+                }
+                main
+            },
+            Self::Leaf { index } => [*index].into_iter().collect()
+        }
+    }
+
+    /// Get the one server whose region contains this position
+    pub fn owner(&self, position: Vec3, region: Aabb) -> usize {
+        match self {
+            Self::Split { split, children } => {
+                let side = |p: f32, s: f32| if p < s {0} else {1};
+                let (x, y, z) = (side(position.x, split.x), side(position.y, split.y), side(position.z, split.z));
+                children[(x * 2 + y) * 2 + z].owner(position, region.octant(*split, x, y, z))
+            },
+            Self::Leaf { index } => *index
+        }
+    }
+
+    /// Find the smallest subtrees that differ between the two trees, as paths of octant numbers from this node
+    pub fn diff(&self, new: &RegionNode, path: &mut Vec<u8>, out: &mut Vec<RoutingChange>) {
+        match (self, new) {
+            (Self::Split { split: old_split, children: old_children }, Self::Split { split, children }) if old_split == split => {
+                for (i, (old, new)) in old_children.iter().zip(children).enumerate() {
+                    path.push(i as u8);
+                    old.diff(new, path, out);
+                    path.pop();
+                }
+            },
+            (old, new) if old == new => {},
+            _ => out.push(RoutingChange { path: path.clone(), subtree: new.clone() })
+        }
+    }
+
+    /// Get the node at the end of a path of octant numbers
+    fn get_mut(&mut self, path: &[u8]) -> Option<&mut RegionNode> {
+        match path.split_first() {
+            None => Some(self),
+            Some((i, rest)) => match self {
+                Self::Split { children, .. } => children.get_mut(*i as usize)?.get_mut(rest),
+                Self::Leaf { .. } => None,
+            }
+        }
+    }
+}
+
+/// One subtree to replace when applying an update
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoutingChange {
+    pub path: Vec<u8>,
+    pub subtree: RegionNode,
+}
+
+/// Every change made to the tree by one restructuring pass, moving it from epoch - 1 to epoch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoutingUpdate {
+    /// Generation of the table the update applies to
+    pub generation: u64,
+    pub epoch: u64,
+    pub changes: Vec<RoutingChange>,
+}
+
+/// A versioned snapshot of the region tree, published by the coordination server so clients can look up servers locally
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoutingTable {
+    /// Picked at random when the coordination server starts, epochs from different generations describe different trees
+    pub generation: u64,
+    /// Goes up by one every time the tree changes
+    pub epoch: u64,
+    /// The region covered by the whole world
+    pub region: Aabb,
+    /// How close to a border, as a fraction of the region size, a player has to be to also be on the neighbouring server
+    pub border_buffer: f32,
+    pub root: RegionNode,
+}
+
+impl RoutingTable {
+    /// Get the servers that should know about a player at this position, the owner and any neighbours within the border buffer
+    pub fn query(&self, position: Vec3) -> HashSet<usize> {
+        self.root.query(position, self.region, self.border_buffer)
+    }
+
+    /// Get the server that owns this position, which players there should be on
+    pub fn owner(&self, position: Vec3) -> usize {
+        self.root.owner(position, self.region)
+    }
+
+    /// Apply the next update, returns false if it doesn't follow on from this epoch of this generation and a fresh table is needed
+    pub fn apply(&mut self, update: &RoutingUpdate) -> bool {
+        if update.generation != self.generation || update.epoch != self.epoch + 1 {return false;}
+        for change in &update.changes {
+            match self.root.get_mut(&change.path) {
+                Some(node) => *node = change.subtree.clone(),
+                None => return false,
+            }
+        }
+        self.epoch = update.epoch;
+        true
+    }
+}
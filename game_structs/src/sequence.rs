@@ -0,0 +1,57 @@
+use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+/// Whether sequence number a comes after b, allowing for wrapping
+pub fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+/// Milliseconds since the unix epoch, for stamping updates as they are sent
+pub fn timestamp_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Counts of how updates from every sender have arrived
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct SequenceStats {
+    pub accepted: u64,
+    /// Updates that arrived after a newer one from the same sender, or twice, and were dropped
+    pub stale: u64,
+    /// Sequence numbers jumped over when a newer update arrived. Only means lost or late updates when the sender
+    /// numbers every update it sends to us, not when updates are relayed through a server that filters them
+    pub skipped: u64,
+}
+
+/// Latest sequence number seen from each sender, so updates that arrive out of order can be dropped
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    latest: HashMap<Uuid, u32>,
+    pub stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    /// Note an update from a sender, returns false if we already have a newer one and it should be dropped
+    pub fn accept(&mut self, sender: Uuid, seq: u32) -> bool {
+        match self.latest.get(&sender) {
+            Some(latest) if !is_newer(seq, *latest) => {
+                self.stats.stale += 1;
+                false
+            },
+            latest => {
+                if let Some(latest) = latest {
+                    self.stats.skipped += seq.wrapping_sub(*latest) as u64 - 1;
+                }
+                self.latest.insert(sender, seq);
+                self.stats.accepted += 1;
+                true
+            }
+        }
+    }
+
+    /// Forget a sender, their next update is accepted whatever its sequence number
+    pub fn remove(&mut self, sender: &Uuid) {
+        self.latest.remove(sender);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{thread, time::Duration};
+
+use game_structs::{auth::{AuthSecret, AUTH_HEADER}, operations::{ChatMessages, ChatScope, GameEvent, GlobalChat, CHAT_CHANNEL, MAX_CHAT_LENGTH}, reliable::Delivery};
+use uuid::Uuid;
+
+use crate::{Session, SessionStruct};
+
+static PROXIMITY_CHAT_RADIUS: f32 = 50.; // How far proximity chat carries
+static CHAT_POLL_INTERVAL: Duration = Duration::from_millis(250); // How often to swap global chat with the coord
+
+/// Pass on something a player said to everyone on this server in scope, global chat also goes out to the other servers
+pub fn send_chat(session: &mut SessionStruct, player_id: Uuid, scope: ChatScope, text: String) {
+    let text = text.trim();
+    if text.is_empty() {return;}
+    let text: String = text.chars().take(MAX_CHAT_LENGTH).collect();
+    let recipients: Vec<Uuid> = match scope {
+        ChatScope::Proximity => match session.states.get(&player_id) {
+            // The grid also has ghosts, who are told by their own server
+            Some(state) => session.grid.nearby(state.position, PROXIMITY_CHAT_RADIUS).into_iter()
+                .map(|(id, _)| id)
+                .filter(|id| *id != player_id && session.reliable.contains_key(id))
+                .collect(),
+            None => return, // Hasn't reported in yet, so nobody is near them
+        },
+        ChatScope::Region | ChatScope::Global => session.reliable.keys().filter(|id| **id != player_id).copied().collect(),
+    };
+    if scope == ChatScope::Global {
+        session.outgoing_chat.push(GlobalChat { id: 0, origin: String::new(), player_id, text: text.clone() }); // The relay fills in where it's from
+    }
+    let event = GameEvent::Chat { player_id, scope, text };
+    for id in recipients {
+        session.send_event(&id, &event, CHAT_CHANNEL, Delivery::Ordered);
+    }
+}
+
+/// Post our players' global chat to the coord, and pass on what players on other servers said to all of ours
+pub fn relay_global_chat(session: Session, coord_address: String, http_address: String, secret: AuthSecret) {
+    let client = reqwest::blocking::Client::new();
+    // Generation of the coord's log and ID of the next message we haven't seen, None until we know where the coord is up to
+    let mut cursor: Option<(u64, u64)> = None;
+    loop {
+        thread::sleep(CHAT_POLL_INTERVAL);
+        let outgoing = std::mem::take(&mut session.write().unwrap().outgoing_chat);
+        for mut message in outgoing {
+            message.origin = http_address.clone();
+            // Chat is best effort, if the coord can't be reached the message only reaches this server
+            let _ = client.post(format!("{}/send_chat", coord_address))
+                .header(AUTH_HEADER, &secret.0)
+                .json(&message)
+                .send();
+        }
+
+        let query = match cursor {
+            Some((generation, next)) => vec![("generation", generation), ("since", next)],
+            None => vec![],
+        };
+        let chat = match client.get(format!("{}/get_chat", coord_address))
+            .header(AUTH_HEADER, &secret.0)
+            .query(&query)
+            .send().and_then(|r| r.error_for_status()).and_then(|r| r.json::<ChatMessages>()) {
+            Ok(c) => c,
+            Err(_) => continue, // Try again next time
+        };
+        // If the coord restarted it sends everything it has, since it was all said after we last heard from it
+        let next = match (chat.messages.last(), cursor) {
+            (Some(last), _) => last.id + 1,
+            (None, Some((generation, next))) if generation == chat.generation => next,
+            (None, _) => 0,
+        };
+        let first_poll = cursor.is_none();
+        cursor = Some((chat.generation, next));
+        if first_poll {continue;} // Said before we started, nobody here was around to hear it
+
+        let mut session = session.write().unwrap();
+        for message in chat.messages.into_iter().filter(|m| m.origin != http_address) {
+            session.broadcast_event(&GameEvent::Chat { player_id: message.player_id, scope: ChatScope::Global, text: message.text }, CHAT_CHANNEL, Delivery::Ordered);
+        }
+    }
+}
@@ -1,40 +1,128 @@
+use std::{collections::HashMap, time::Instant};
 use uuid::Uuid;
 use rocket::{
     get, post,
+    http::Status,
     serde::json::Json, State,
 };
 use game_structs::{
-    operations::PlayerRegister
+    Aabb,
+    Player,
+    attributes::{client_settable, client_valid},
+    auth::{AuthSecret, AuthToken, ServiceAuth},
+    density::{DensityHistogram, DENSITY_RESOLUTION},
+    encoding::Encoding,
+    operations::{AttributeUpdate, PlayerRegister, PlayerRegistered, GameEvent, EVENT_CHANNEL},
+    reliable::{ReliableEndpoint, Delivery}
 };
-use crate::Session;
+use crate::{Session, logic::PlayerEvent};
+
+static SUPPORTED_ENCODINGS: [Encoding; 2] = [Encoding::Bincode, Encoding::Compact]; // Encodings clients can pick from
 
 #[post("/register_player", format = "json", data = "<player_register>")]
-pub fn register_player(session: &State<Session>, player_register: Json<PlayerRegister>) -> String {
-    let mut session = session.write().unwrap();
+pub fn register_player(session: &State<Session>, secret: &State<AuthSecret>, token: AuthToken, player_register: Json<PlayerRegister>) -> Result<String, Status> {
     let mut player = player_register.player.clone();
     if player.id == Uuid::default() { // If player already has an ID, don't assign a new one
         player.id = Uuid::new_v4();
+    } else if !token.is_player(player.id) { // Only the player themselves can reuse their ID
+        return Err(Status::Unauthorized);
+    }
+    let mut session = session.write().unwrap();
+    if !session.players.contains_key(&player.id) {
+        session.player_events.push(PlayerEvent::Joined(player.id));
+    }
+    // Players coming over from a neighbouring server were already visible as ghosts
+    if !session.players.contains_key(&player.id) && session.ghosts.remove(&player.id).is_none() {
+        session.broadcast_event(&GameEvent::PlayerJoined { player_id: player.id }, EVENT_CHANNEL, Delivery::Ordered);
+    }
+    // Pick up where the last server left off, if it passed on what it knew about the player
+    if let Some((migration, _)) = session.incoming.remove(&player.id) {
+        migration.import(&mut session);
     }
-    session.players.insert(player.id, player.clone());
+    session.players.entry(player.id).or_insert_with(|| Player::new(player.id));
+    // Only take the attributes players are allowed to set, the rest stay as this server or the last one had them
+    let attributes = player.attributes.iter()
+        .filter(|(name, value)| client_settable(name) && client_valid(value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    session.set_attributes(&player.id, attributes);
+    session.reliable.insert(player.id, ReliableEndpoint::default()); // New session, start the channel again
+    session.last_seen.insert(player.id, Instant::now());
     session.addresses.insert(player.id, player_register.address.clone());
-    serde_json::to_string(&player.id).unwrap()
+    let session_key = Uuid::new_v4().as_u128() as u64;
+    if let Some(old_key) = session.session_keys.insert(player.id, session_key) {
+        session.key_owners.remove(&old_key);
+    }
+    session.key_owners.insert(session_key, player.id);
+    // Use the first encoding the client offered that we know, all clients can do bincode
+    let encoding = player_register.encodings.iter().copied()
+        .find(|e| SUPPORTED_ENCODINGS.contains(e))
+        .unwrap_or(Encoding::Bincode);
+    session.encodings.insert(player.id, encoding);
+    session.acks.remove(&player.id);
+    Ok(serde_json::to_string(&PlayerRegistered {
+        player_id: player.id,
+        token: secret.sign_player(player.id),
+        session_key,
+        encoding,
+        quantizer: session.quantizer,
+        snapshot: session.snapshot()
+    }).unwrap())
 }
 
 #[post("/unregister_player", format = "json", data = "<player_id>")]
-pub fn unregister_player(session: &State<Session>, player_id: Json<Uuid>) {
-    let mut session = session.write().unwrap();
-    session.players.remove(&player_id);
-    session.addresses.remove(&player_id);
+pub fn unregister_player(session: &State<Session>, token: AuthToken, player_id: Json<Uuid>) -> Status {
+    if !token.is_player(*player_id) {
+        return Status::Unauthorized;
+    }
+    session.write().unwrap().remove_player(&player_id);
+    Status::Ok
 }
 
+/// Players on this server, along with players on neighbouring servers close enough to be seen from here
 #[get("/get_players")]
 pub fn get_players(session: &State<Session>) -> String {
-    serde_json::to_string(&session.read().unwrap()
-        .players.clone()).unwrap()
+    let session = session.read().unwrap();
+    let mut players = session.players.clone();
+    players.extend(session.ghosts.iter().map(|(id, ghost)| (*id, Player { id: *id, attributes: ghost.attributes.clone() })));
+    serde_json::to_string(&players).unwrap()
+}
+
+/// Change a player's attributes. Players can only change their own, and only the ones clients are allowed to set
+#[post("/set_attributes", format = "json", data = "<update>")]
+pub fn set_attributes(session: &State<Session>, token: AuthToken, update: Json<AttributeUpdate>) -> Status {
+    if !token.is_player(update.player_id) {
+        return Status::Unauthorized;
+    }
+    if !token.is_service() && update.attributes.keys().any(|name| !client_settable(name)) {
+        return Status::Forbidden;
+    }
+    if !token.is_service() && update.attributes.values().any(|value| !client_valid(value)) {
+        return Status::BadRequest;
+    }
+    let update = update.into_inner();
+    match session.write().unwrap().set_attributes(&update.player_id, update.attributes) {
+        true => Status::Ok,
+        false => Status::NotFound,
+    }
 }
 
 #[get("/get_num_players")]
-pub fn get_num_players(session: &State<Session>) -> String {
+pub fn get_num_players(session: &State<Session>, _auth: ServiceAuth) -> String {
     serde_json::to_string(&session.read().unwrap()
         .players.len()).unwrap()
 }
+
+/// Latest position of every player, used by the coordination server to work out who to hand off
+#[get("/get_positions")]
+pub fn get_positions(session: &State<Session>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&session.read().unwrap()
+        .positions().collect::<HashMap<_, _>>()).unwrap()
+}
+
+/// Coarse histogram of where players in a region are, used by the coordination server to plan splits
+#[post("/get_density", format = "json", data = "<region>")]
+pub fn get_density(session: &State<Session>, _auth: ServiceAuth, region: Json<Aabb>) -> String {
+    let session = session.read().unwrap();
+    serde_json::to_string(&DensityHistogram::new(*region, DENSITY_RESOLUTION, session.positions().map(|(_, p)| p))).unwrap()
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+
+use game_structs::{
+    Quat,
+    Vec3,
+    attributes::{self, Attributes},
+    auth::AuthToken,
+    entities::{ChangeEntity, EntityKind, EntityUpdate, ReplicatedEntity, SpawnEntity},
+};
+use rocket::{get, post, http::Status, serde::json::Json, State};
+use uuid::Uuid;
+
+use crate::{Session, SessionStruct};
+
+static MAX_ENTITIES_PER_OWNER: usize = 32; // Entities one player can own at once
+static MAX_DATA_SIZE: u64 = 512; // Bytes of custom data an entity can carry, it is sent to every player whenever it changes
+static MAX_KIND_LENGTH: usize = 32; // Bytes in the name of a game's own kind of entity, it is sent with every spawn
+
+/// An entity, and how many times it has moved
+#[derive(Debug, Clone)]
+struct EntityState {
+    entity: ReplicatedEntity,
+    seq: u32,
+}
+
+/// Every entity on this server
+#[derive(Debug, Clone, Default)]
+pub struct Entities {
+    entities: HashMap<Uuid, EntityState>,
+    moved: HashSet<Uuid>, // Entities that moved since the last tick
+}
+
+impl Entities {
+    pub fn get(&self, id: &Uuid) -> Option<&ReplicatedEntity> {
+        self.entities.get(id).map(|s| &s.entity)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &ReplicatedEntity> {
+        self.entities.values().map(|s| &s.entity)
+    }
+
+    pub fn insert(&mut self, entity: ReplicatedEntity) {
+        self.entities.insert(entity.id, EntityState { entity, seq: 0 });
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<ReplicatedEntity> {
+        self.moved.remove(id);
+        self.entities.remove(id).map(|s| s.entity)
+    }
+
+    /// Move an entity, it is sent to nearby players on the next tick
+    pub fn move_to(&mut self, id: &Uuid, position: Option<Vec3>, rotation: Option<Quat>) {
+        if let Some(state) = self.entities.get_mut(id) {
+            if position.is_none() && rotation.is_none() {return;}
+            state.entity.position = position.unwrap_or(state.entity.position);
+            state.entity.rotation = rotation.unwrap_or(state.entity.rotation);
+            state.seq = state.seq.wrapping_add(1);
+            self.moved.insert(*id);
+        }
+    }
+
+    /// Change an entity's data, returning the values that were actually different
+    pub fn set_data(&mut self, id: &Uuid, data: Attributes) -> Attributes {
+        match self.entities.get_mut(id) {
+            Some(state) => attributes::merge(&mut state.entity.data, data),
+            None => Attributes::new(),
+        }
+    }
+
+    /// Entities belonging to a player
+    pub fn owned_by(&self, owner: &Uuid) -> Vec<Uuid> {
+        self.all().filter(|e| e.owner.as_ref() == Some(owner)).map(|e| e.id).collect()
+    }
+
+    /// Where every entity that moved since the last call is now
+    pub fn take_moved(&mut self) -> Vec<EntityUpdate> {
+        let entities = &self.entities;
+        self.moved.drain()
+            .filter_map(|id| entities.get(&id))
+            .map(|s| EntityUpdate { id: s.entity.id, seq: s.seq, position: s.entity.position, rotation: s.entity.rotation })
+            .collect()
+    }
+}
+
+/// Whether the request can act on an entity with this owner, players for their own and services for any
+fn can_control(token: &AuthToken, owner: Option<Uuid>) -> bool {
+    match owner {
+        Some(owner) => token.is_player(owner),
+        None => token.is_service(),
+    }
+}
+
+/// Whether an entity can be put here, inside the world and facing a real direction
+fn valid_transform(session: &SessionStruct, position: Option<Vec3>, rotation: Option<Quat>) -> bool {
+    position.is_none_or(|p| session.quantizer.region.contains(p)) && rotation.is_none_or(|r| r.is_finite())
+}
+
+/// Whether an entity's data is small enough to send to everyone
+fn valid_data(data: &Attributes) -> bool {
+    bincode::serialized_size(data).is_ok_and(|size| size <= MAX_DATA_SIZE)
+}
+
+/// Whether an entity's kind has a short enough name
+fn valid_kind(kind: &EntityKind) -> bool {
+    match kind {
+        EntityKind::Other(name) => name.len() <= MAX_KIND_LENGTH,
+        _ => true,
+    }
+}
+
+/// Create an entity, returning its ID
+#[post("/spawn_entity", format = "json", data = "<spawn>")]
+pub fn spawn_entity(session: &State<Session>, token: AuthToken, spawn: Json<SpawnEntity>) -> Result<String, Status> {
+    if !can_control(&token, spawn.owner) {
+        return Err(Status::Unauthorized);
+    }
+    let mut session = session.write().unwrap();
+    if !valid_transform(&session, Some(spawn.position), Some(spawn.rotation)) {
+        return Err(Status::BadRequest);
+    }
+    if !valid_kind(&spawn.kind) || !valid_data(&spawn.data) {
+        return Err(Status::PayloadTooLarge);
+    }
+    if spawn.owner.is_some_and(|owner| session.entities.owned_by(&owner).len() >= MAX_ENTITIES_PER_OWNER) {
+        return Err(Status::Forbidden);
+    }
+    let entity = ReplicatedEntity::new(spawn.into_inner());
+    let id = entity.id;
+    session.spawn_entity(entity);
+    Ok(serde_json::to_string(&id).unwrap())
+}
+
+/// Move an entity or change its data
+#[post("/change_entity", format = "json", data = "<change>")]
+pub fn change_entity(session: &State<Session>, token: AuthToken, change: Json<ChangeEntity>) -> Status {
+    let mut session = session.write().unwrap();
+    let (owner, mut data) = match session.entities.get(&change.id) {
+        Some(e) => (e.owner, e.data.clone()),
+        None => return Status::NotFound,
+    };
+    if !can_control(&token, owner) {
+        return Status::Unauthorized;
+    }
+    if !valid_transform(&session, change.position, change.rotation) {
+        return Status::BadRequest;
+    }
+    attributes::merge(&mut data, change.data.clone());
+    if !valid_data(&data) {
+        return Status::PayloadTooLarge;
+    }
+    session.change_entity(change.into_inner());
+    Status::Ok
+}
+
+#[post("/despawn_entity", format = "json", data = "<id>")]
+pub fn despawn_entity(session: &State<Session>, token: AuthToken, id: Json<Uuid>) -> Status {
+    let mut session = session.write().unwrap();
+    let owner = match session.entities.get(&id) {
+        Some(e) => e.owner,
+        None => return Status::NotFound,
+    };
+    if !can_control(&token, owner) {
+        return Status::Unauthorized;
+    }
+    session.despawn_entity(&id);
+    Status::Ok
+}
+
+/// Every entity on this server
+#[get("/get_entities")]
+pub fn get_entities(session: &State<Session>) -> String {
+    serde_json::to_string(&session.read().unwrap()
+        .entities.all().collect::<Vec<_>>()).unwrap()
+}
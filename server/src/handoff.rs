@@ -0,0 +1,83 @@
+use std::{collections::HashSet, thread, time::{Duration, Instant}};
+
+use game_structs::{
+    auth::{AuthSecret, ServiceAuth, AUTH_HEADER},
+    operations::{HandoffComplete, HandoffRequest}
+};
+use rocket::{post, serde::json::Json, State};
+use uuid::Uuid;
+
+use crate::Session;
+
+static NOTIFY_INTERVAL: Duration = Duration::from_secs(1); // How often to remind a departing player to move, in case the notice was lost
+
+/// A player who has been told to move to another server
+#[derive(Debug, Clone)]
+pub struct PendingHandoff {
+    pub handoff_id: u64,
+    pub servers: HashSet<usize>,
+    pub deadline: Instant,
+    pub last_notified: Option<Instant>,
+}
+
+#[post("/handoff", format = "json", data = "<request>")]
+pub fn start_handoff(session: &State<Session>, request: Json<HandoffRequest>, _auth: ServiceAuth) {
+    let mut session = session.write().unwrap();
+    let deadline = Instant::now() + Duration::from_millis(request.deadline_millis);
+    for (player_id, servers) in request.players.iter() {
+        if session.players.contains_key(player_id) {
+            session.handoffs.insert(*player_id, PendingHandoff {
+                handoff_id: request.handoff_id,
+                servers: servers.clone(),
+                deadline,
+                last_notified: None,
+            });
+        }
+    }
+}
+
+/// Notify departing players, and report to the coordination server once they have left or run out of time.
+/// Notices go out through the streaming task with the next tick
+pub fn track_handoffs(session: Session, coord_address: String, secret: AuthSecret) {
+    loop {
+        let mut finished = vec![];
+        {
+            let mut session = session.write().unwrap();
+            let now = Instant::now();
+            let mut timed_out = vec![];
+            let players = session.players.keys().cloned().collect::<HashSet<Uuid>>();
+            let mut notices = vec![];
+            session.handoffs.retain(|player_id, pending| {
+                if !players.contains(player_id) {
+                    // Player left by themselves
+                    finished.push(HandoffComplete { handoff_id: pending.handoff_id, player_id: *player_id, timed_out: false });
+                    return false;
+                }
+                if now > pending.deadline {
+                    timed_out.push(*player_id);
+                    finished.push(HandoffComplete { handoff_id: pending.handoff_id, player_id: *player_id, timed_out: true });
+                    return false;
+                }
+                if pending.last_notified.is_none_or(|t| now - t > NOTIFY_INTERVAL) {
+                    notices.push((*player_id, pending.servers.clone()));
+                    pending.last_notified = Some(now);
+                }
+                true
+            });
+            session.handoff_notices.extend(notices);
+            // Drop players who never moved
+            for player_id in timed_out {
+                session.remove_player(&player_id);
+            }
+        }
+
+        for complete in finished {
+            let _ = reqwest::blocking::Client::new().post(format!("{}/handoff_complete", coord_address)).header("Content-Type", "application/json")
+                .header(AUTH_HEADER, &secret.0)
+                .body(serde_json::to_string(&complete).unwrap())
+                .send();
+        }
+
+        thread::sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,31 @@
+use std::{sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
+
+use tokio::{sync::watch, time};
+use uuid::Uuid;
+
+use crate::{Session, metrics::Metrics};
+
+static MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1); // Don't check for idle players more often than this
+
+/// Unregister players who haven't sent anything for longer than the timeout, such as clients that crashed
+/// without unregistering. Runs until shutdown is signalled
+pub async fn expire_idle_players(session: Session, metrics: Arc<Metrics>, timeout: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = time::interval((timeout / 4).max(MIN_CHECK_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.changed() => return,
+        }
+
+        let mut session = session.write().unwrap();
+        let now = Instant::now();
+        let idle: Vec<Uuid> = session.last_seen.iter()
+            .filter(|(_, seen)| now.duration_since(**seen) > timeout)
+            .map(|(player_id, _)| *player_id)
+            .collect();
+        for player_id in idle {
+            session.remove_player(&player_id);
+            metrics.players_timed_out.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+use game_structs::Vec3;
+use uuid::Uuid;
+
+/// How far away players can see each other, and how often they hear about players further out
+#[derive(Debug, Clone, Copy)]
+pub struct InterestConfig {
+    /// Players within this distance get every update
+    pub radius: f32,
+    /// Players beyond the radius but within this distance get updates on one in every far_every ticks, 0 to disable
+    pub far_radius: f32,
+    pub far_every: u64,
+}
+
+impl InterestConfig {
+    /// Furthest distance a player can get updates from
+    pub fn max_radius(&self) -> f32 {
+        self.radius.max(self.far_radius)
+    }
+
+    /// Whether a player this far away should get updates on this tick
+    pub fn wants(&self, distance: f32, n: u64) -> bool {
+        distance <= self.radius || (distance <= self.far_radius && n.is_multiple_of(self.far_every.max(1)))
+    }
+}
+
+type Cell = (i32, i32, i32);
+
+/// Buckets players into a uniform grid so nearby players can be found without checking everyone
+#[derive(Debug, Clone)]
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<Cell, HashSet<Uuid>>,
+    positions: HashMap<Uuid, (Cell, Vec3)>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self { cell_size, cells: HashMap::new(), positions: HashMap::new() }
+    }
+
+    fn cell(&self, position: Vec3) -> Cell {
+        let cell = (position / self.cell_size).floor();
+        (cell.x as i32, cell.y as i32, cell.z as i32)
+    }
+
+    /// Move a player to their latest position
+    pub fn update(&mut self, player_id: Uuid, position: Vec3) {
+        let cell = self.cell(position);
+        if let Some((old_cell, _)) = self.positions.insert(player_id, (cell, position)) {
+            if old_cell == cell {return;}
+            if let Some(players) = self.cells.get_mut(&old_cell) {
+                players.remove(&player_id);
+                if players.is_empty() {
+                    self.cells.remove(&old_cell);
+                }
+            }
+        }
+        self.cells.entry(cell).or_default().insert(player_id);
+    }
+
+    pub fn remove(&mut self, player_id: &Uuid) {
+        if let Some((cell, _)) = self.positions.remove(player_id) {
+            if let Some(players) = self.cells.get_mut(&cell) {
+                players.remove(player_id);
+                if players.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Every player within the radius of a position, along with how far away they are
+    pub fn nearby(&self, position: Vec3, radius: f32) -> Vec<(Uuid, f32)> {
+        let (cx, cy, cz) = self.cell(position);
+        let reach = (radius / self.cell_size).ceil() as i32;
+        let mut out = vec![];
+        for x in cx - reach..=cx + reach {
+            for y in cy - reach..=cy + reach {
+                for z in cz - reach..=cz + reach {
+                    for player_id in self.cells.get(&(x, y, z)).into_iter().flatten() {
+                        let distance = self.positions[player_id].1.distance(position);
+                        if distance <= radius {
+                            out.push((*player_id, distance));
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
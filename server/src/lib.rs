@@ -0,0 +1,354 @@
+mod chat;
+mod endpoints;
+pub mod entities;
+pub mod handoff;
+mod idle;
+pub mod interest;
+pub mod logic;
+mod metrics;
+pub mod migration;
+pub mod replication;
+mod streaming;
+pub mod validation;
+
+use std::thread;
+use tokio::sync::{mpsc, watch};
+use std::{sync::{Arc, Mutex, RwLock}, collections::{HashMap, HashSet}, time::{Duration, Instant}};
+use uuid::Uuid;
+use rocket::routes;
+use game_structs::{Player, Vec3, Aabb, attributes::Attributes, entities::{ChangeEntity, ReplicatedEntity}, auth::AuthSecret, operations::{PositionUpdate, GameEvent, GlobalChat, EVENT_CHANNEL}, encoding::{Encoding, Quantizer}, sequence::SequenceTracker, reliable::{ReliableEndpoint, Delivery}};
+use endpoints::*;
+use handoff::*;
+use streaming::*;
+use metrics::{Metrics, get_metrics};
+use interest::{InterestConfig, SpatialGrid};
+use logic::{GameLogic, Logic, PlayerEvent};
+use entities::{Entities, spawn_entity, change_entity, despawn_entity, get_entities};
+use migration::{PlayerMigration, migrate_player, transfer_player};
+use replication::Ghost;
+use validation::{ValidationConfig, Ownership, ViolationReport, get_violations};
+use clap::Parser;
+
+/// The server's authoritative view of a player
+#[derive(Debug, Clone)]
+pub struct PlayerState {
+    pub position: Vec3,
+    pub seq: u32, // Sequence number of the update the position came from
+    pub timestamp: u64, // When the player sent that update
+    pub updated: Instant, // When the player last reported in
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStruct {
+    pub players: HashMap<Uuid, Player>,
+    pub addresses: HashMap<Uuid, String>,
+    pub session_keys: HashMap<Uuid, u64>, // Key each player has to send with their updates
+    pub key_owners: HashMap<u64, Uuid>, // Which player each session key belongs to, for messages that only carry the key
+    pub encodings: HashMap<Uuid, Encoding>, // How each player's updates are put on the wire
+    pub acks: HashMap<Uuid, u32>, // Latest compact snapshot each player has acknowledged
+    pub quantizer: Quantizer, // Bounds positions are quantised to for the compact encoding
+    pub states: HashMap<Uuid, PlayerState>, // Latest state of each player that has reported in
+    pub grid: SpatialGrid, // Where each player that has reported in is, for finding who is near who
+    pub handoffs: HashMap<Uuid, PendingHandoff>, // Players being moved to another server
+    pub ownership: Option<Ownership>, // Which region this server covers, once the coord has told us
+    pub violations: HashMap<Uuid, ViolationReport>, // Players who have broken movement rules
+    pub corrections: HashMap<Uuid, PositionUpdate>, // Positions to send back to players whose moves were clamped
+    pub handoff_notices: HashMap<Uuid, HashSet<usize>>, // Servers to tell departing players to move to, sent with the next tick
+    pub reliable: HashMap<Uuid, ReliableEndpoint>, // Reliable channel to each player, for events that can't be lost
+    pub sequences: SequenceTracker, // Latest update each player has sent, so late ones can be dropped
+    pub last_seen: HashMap<Uuid, Instant>, // When we last heard anything from each player, including registering
+    pub ghosts: HashMap<Uuid, Ghost>, // Players on neighbouring servers near enough to the border for our players to see
+    pub peer_ticks: HashMap<usize, (u64, u64)>, // When each neighbouring server started and the latest tick we've had from it, so old updates can't be replayed
+    pub incoming: HashMap<Uuid, (PlayerMigration, Instant)>, // State passed on by other servers for players who haven't registered yet
+    pub entities: Entities, // Everything other than players that clients need to see
+    pub player_events: Vec<PlayerEvent>, // Players who joined or left since the game logic last ran
+    pub outgoing_chat: Vec<GlobalChat> // Global chat from our players waiting to go to the coord
+}
+
+impl SessionStruct {
+    pub fn new(grid_cell_size: f32, quantizer: Quantizer) -> Self {
+        Self {
+            players: HashMap::new(),
+            addresses: HashMap::new(),
+            session_keys: HashMap::new(),
+            key_owners: HashMap::new(),
+            encodings: HashMap::new(),
+            acks: HashMap::new(),
+            quantizer,
+            states: HashMap::new(),
+            grid: SpatialGrid::new(grid_cell_size),
+            handoffs: HashMap::new(),
+            ownership: None,
+            violations: HashMap::new(),
+            corrections: HashMap::new(),
+            handoff_notices: HashMap::new(),
+            reliable: HashMap::new(),
+            sequences: SequenceTracker::default(),
+            last_seen: HashMap::new(),
+            ghosts: HashMap::new(),
+            peer_ticks: HashMap::new(),
+            incoming: HashMap::new(),
+            entities: Entities::default(),
+            player_events: Vec::new(),
+            outgoing_chat: Vec::new(),
+        }
+    }
+
+    /// Record the latest position a player reported
+    pub fn update_state(&mut self, update: &PositionUpdate) {
+        self.states.insert(update.player_id, PlayerState { position: update.position, seq: update.seq, timestamp: update.timestamp, updated: Instant::now() });
+        self.grid.update(update.player_id, update.position);
+    }
+
+    /// Forget a player, and tell everyone else they left
+    pub fn remove_player(&mut self, player_id: &Uuid) {
+        let removed = self.players.remove(player_id).is_some();
+        self.addresses.remove(player_id);
+        if let Some(session_key) = self.session_keys.remove(player_id) {
+            self.key_owners.remove(&session_key);
+        }
+        self.encodings.remove(player_id);
+        self.acks.remove(player_id);
+        self.states.remove(player_id);
+        self.corrections.remove(player_id);
+        self.handoff_notices.remove(player_id);
+        self.grid.remove(player_id);
+        self.reliable.remove(player_id);
+        self.sequences.remove(player_id);
+        self.last_seen.remove(player_id);
+        for id in self.entities.owned_by(player_id) {
+            self.despawn_entity(&id);
+        }
+        if removed {
+            self.player_events.push(PlayerEvent::Left(*player_id));
+            self.broadcast_event(&GameEvent::PlayerLeft { player_id: *player_id }, EVENT_CHANNEL, Delivery::Ordered);
+        }
+    }
+
+    /// Change a player's attributes and tell everyone which ones changed, returns false if the player isn't here
+    pub fn set_attributes(&mut self, player_id: &Uuid, changes: Attributes) -> bool {
+        let changed = match self.players.get_mut(player_id) {
+            Some(player) => player.set_attributes(changes),
+            None => return false,
+        };
+        if !changed.is_empty() {
+            self.broadcast_event(&GameEvent::AttributesChanged { player_id: *player_id, attributes: changed }, EVENT_CHANNEL, Delivery::Ordered);
+        }
+        true
+    }
+
+    /// Add an entity and tell everyone about it
+    pub fn spawn_entity(&mut self, entity: ReplicatedEntity) {
+        self.broadcast_event(&GameEvent::EntitySpawned(entity.clone()), EVENT_CHANNEL, Delivery::Ordered);
+        self.entities.insert(entity);
+    }
+
+    /// Move an entity and change its data, telling everyone which data changed. Moves go out with the next tick
+    pub fn change_entity(&mut self, change: ChangeEntity) {
+        self.entities.move_to(&change.id, change.position, change.rotation);
+        let changed = self.entities.set_data(&change.id, change.data);
+        if !changed.is_empty() {
+            self.broadcast_event(&GameEvent::EntityChanged { id: change.id, data: changed }, EVENT_CHANNEL, Delivery::Ordered);
+        }
+    }
+
+    /// Remove an entity and tell everyone it's gone
+    pub fn despawn_entity(&mut self, id: &Uuid) {
+        if self.entities.remove(id).is_some() {
+            self.broadcast_event(&GameEvent::EntityDespawned { id: *id }, EVENT_CHANNEL, Delivery::Ordered);
+        }
+    }
+
+    /// Queue an event to be sent reliably to one player
+    pub fn send_event(&mut self, player_id: &Uuid, event: &GameEvent, channel: u8, delivery: Delivery) {
+        if let Some(endpoint) = self.reliable.get_mut(player_id) {
+            let payload = bincode::serialize(event).unwrap();
+            let size = payload.len();
+            if !endpoint.send(channel, delivery, payload) {
+                eprintln!("Dropped a {} byte event too big for the reliable channel", size);
+            }
+        }
+    }
+
+    /// Queue an event to be sent reliably to every player
+    pub fn broadcast_event(&mut self, event: &GameEvent, channel: u8, delivery: Delivery) {
+        let payload = bincode::serialize(event).unwrap();
+        let mut sent = true;
+        for endpoint in self.reliable.values_mut() {
+            sent &= endpoint.send(channel, delivery, payload.clone());
+        }
+        if !sent {
+            eprintln!("Dropped a {} byte event too big for the reliable channel", payload.len());
+        }
+    }
+
+    /// Latest position of every player, as updates that can be sent to clients
+    pub fn snapshot(&self) -> Vec<PositionUpdate> {
+        self.states.iter()
+            .map(|(player_id, state)| PositionUpdate { player_id: *player_id, position: state.position, seq: state.seq, timestamp: state.timestamp })
+            .collect()
+    }
+
+    /// Latest position of every player
+    pub fn positions(&self) -> impl Iterator<Item = (&Uuid, Vec3)> {
+        self.states.iter().map(|(player_id, state)| (player_id, state.position))
+    }
+}
+
+pub type Session = Arc<RwLock<SessionStruct>>;
+
+/// Run a server with the game's own rules until Rocket shuts down
+///
+/// ```no_run
+/// use clap::Parser;
+/// use server::{Config, SessionStruct, entities::Entities, logic::GameLogic};
+/// use uuid::Uuid;
+///
+/// /// Greets players with how many entities are around
+/// struct Greeter;
+///
+/// fn entity_count(entities: &Entities) -> usize {
+///     entities.all().count()
+/// }
+///
+/// impl GameLogic for Greeter {
+///     fn player_joined(&mut self, session: &mut SessionStruct, player_id: Uuid) {
+///         println!("{} joined with {} entities around", player_id, entity_count(&session.entities));
+///     }
+/// }
+///
+/// #[rocket::main]
+/// async fn main() -> Result<(), rocket::Error> {
+///     server::run(Config::parse(), Box::new(Greeter)).await
+/// }
+/// ```
+pub async fn run(config: Config, logic: Box<dyn GameLogic>) -> Result<(), rocket::Error> {
+
+    // Create channel, and a way to tell the streaming tasks to stop
+    let (sender, receiver): (mpsc::Sender<PositionUpdate>, mpsc::Receiver<PositionUpdate>) = mpsc::channel(UPDATE_QUEUE_SIZE);
+    let (shutdown_sender, shutdown) = watch::channel(false);
+
+    // Create session
+    let interest = InterestConfig {
+        radius: config.interest_radius,
+        far_radius: config.far_radius,
+        far_every: config.far_every,
+    };
+    let validation = ValidationConfig {
+        max_speed: config.max_speed,
+        world: Aabb::new(Vec3::ONE * -config.world_size / 2., Vec3::ONE * config.world_size / 2.),
+    };
+    let session = Arc::new(RwLock::new(SessionStruct::new(interest.radius, Quantizer { region: validation.world })));
+    let (session1, session2, session3) = (session.clone(), session.clone(), session.clone());
+    let secret = AuthSecret(config.secret);
+
+    // Create metrics
+    let metrics = Arc::new(Metrics::default());
+    let (metrics1, metrics2) = (metrics.clone(), metrics.clone());
+
+    // Create send/receive sockets
+    let send_socket = Arc::new(tokio::net::UdpSocket::bind(format!("127.0.0.1:{}", config.send)).await.expect("Failed to bind send socket"));
+    let receive_socket = tokio::net::UdpSocket::bind(format!("127.0.0.1:{}", config.receive)).await.expect("Failed to bind receive socket");
+
+    let logic: Logic = Arc::new(Mutex::new(logic));
+
+    // Launch sender and receiver tasks on Rocket's runtime
+    let sender_handle = tokio::spawn(send_positions(session1, receiver, send_socket, metrics1, interest, config.tick_rate, secret.clone(), logic.clone(), shutdown.clone()));
+    let receive_handle = tokio::spawn(receive_positions(session2, sender, receive_socket, metrics2, validation, secret.clone(), logic, shutdown.clone()));
+    if config.idle_timeout > 0. {
+        tokio::spawn(idle::expire_idle_players(session.clone(), metrics.clone(), Duration::from_secs_f32(config.idle_timeout), shutdown));
+    }
+    let (coord, session4) = (config.coord.clone(), session.clone());
+    let http_address = format!("http://127.0.0.1:{}", config.main);
+    thread::spawn(move || {
+        validation::track_ownership(session4, coord, http_address);
+    });
+    let (coord, secret1) = (config.coord.clone(), secret.clone());
+    thread::spawn(move || {
+        track_handoffs(session3, coord, secret1);
+    });
+    let (coord, session5, secret2) = (config.coord.clone(), session.clone(), secret.clone());
+    let http_address = format!("http://127.0.0.1:{}", config.main);
+    thread::spawn(move || {
+        chat::relay_global_chat(session5, coord, http_address, secret2);
+    });
+
+    // Launch Rocket server
+    let figment = rocket::Config::figment()
+        .merge(("port", config.main));
+
+    let result = rocket::custom(figment)
+        .mount("/", routes![register_player, unregister_player, get_players, set_attributes, get_num_players, get_positions, get_density, get_metrics, start_handoff, get_violations, migrate_player, transfer_player, spawn_entity, change_entity, despawn_entity, get_entities])
+        .manage(session)
+        .manage(metrics)
+        .manage(secret)
+        .launch().await;
+
+    // Rocket has shut down, stop streaming and wait for the tasks to finish
+    let _ = shutdown_sender.send(true);
+    sender_handle.await.expect("Sending task panicked");
+    receive_handle.await.expect("Receiving task panicked");
+    result
+}
+
+/// Settings for a game server
+#[derive(Parser, Debug)]
+#[clap(name = "Server")]
+pub struct Config {
+    /// The port to send updates to the server from
+    #[clap(short, long)]
+    pub send: String,
+    
+    /// The port to receive updates from the server
+    #[clap(short, long)]
+    pub receive: String,
+
+    /// The port number the Rocket server should run on
+    #[clap(short, long)]
+    pub main: i32,
+
+    /// Secret shared with the coordination server, used to authenticate services and sign player tokens. Best set through the environment, where other users can't see it like they can the command line
+    #[clap(long, env = "GAME_SECRET", hide_env_values = true)]
+    pub secret: String,
+
+    /// Address of the coordination server
+    #[clap(long, default_value = "http://127.0.0.1:8002")]
+    pub coord: String,
+
+    /// Players only get updates from players within this distance of them, also the size of the grid cells used to find them
+    #[clap(long, default_value = "64", parse(try_from_str = parse_positive))]
+    pub interest_radius: f32,
+
+    /// Players within this distance, but outside the interest radius, get fewer updates, 0 to disable
+    #[clap(long, default_value = "0")]
+    pub far_radius: f32,
+
+    /// Players in the far radius get updates on one in this many ticks
+    #[clap(long, default_value = "4")]
+    pub far_every: u64,
+
+    /// How many times a second to send snapshots to clients
+    #[clap(long, default_value = "20", parse(try_from_str = parse_positive))]
+    pub tick_rate: f32,
+
+    /// Fastest a player is allowed to move, in units per second
+    #[clap(long, default_value = "15", parse(try_from_str = parse_positive))]
+    pub max_speed: f32,
+
+    /// Size of the world, players are kept inside it
+    #[clap(long, default_value = "1024", parse(try_from_str = parse_positive))]
+    pub world_size: f32,
+
+    /// Unregister players we haven't heard from in this many seconds, 0 to never
+    #[clap(long, default_value = "30")]
+    pub idle_timeout: f32
+}
+
+/// Parse a number that has to be above zero, such as a size the world is divided by
+fn parse_positive(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if v > 0. && v.is_finite() => Ok(v),
+        Ok(_) => Err("must be greater than zero".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
\ No newline at end of file
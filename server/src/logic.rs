@@ -0,0 +1,61 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use game_structs::operations::{ChatScope, PositionUpdate};
+use uuid::Uuid;
+
+use crate::SessionStruct;
+
+/// Something that happened to a player, passed to the game logic at the start of the next tick
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    Joined(Uuid),
+    Left(Uuid),
+}
+
+/// Authoritative rules a game plugs into the server. Every hook is called with the session locked, so they
+/// should be quick. Hooks can emit messages through the session, such as `broadcast_event`, `set_attributes`
+/// or `spawn_entity`
+pub trait GameLogic: Send {
+    /// A player registered with this server, or came over from another one
+    fn player_joined(&mut self, _session: &mut SessionStruct, _player_id: Uuid) {}
+
+    /// A player left this server, their state has already been removed
+    fn player_left(&mut self, _session: &mut SessionStruct, _player_id: Uuid) {}
+
+    /// A player's update passed validation. Return it, changed if need be, or None to drop it
+    fn player_update(&mut self, _session: &mut SessionStruct, update: PositionUpdate) -> Option<PositionUpdate> {
+        Some(update)
+    }
+
+    /// A player did something. Return the action to pass on to everyone else, or None to drop it
+    fn player_action(&mut self, _session: &mut SessionStruct, _player_id: Uuid, action: String) -> Option<String> {
+        Some(action)
+    }
+
+    /// A player said something. Return the text to pass on to everyone in scope, or None to drop it
+    fn player_chat(&mut self, _session: &mut SessionStruct, _player_id: Uuid, _scope: ChatScope, text: String) -> Option<String> {
+        Some(text)
+    }
+
+    /// Runs at the start of every tick, before anything is sent
+    fn tick(&mut self, _session: &mut SessionStruct, _tick: u64, _tick_length: Duration) {}
+}
+
+/// The game logic, shared by the streaming tasks
+pub type Logic = Arc<Mutex<Box<dyn GameLogic>>>;
+
+/// No rules, players' updates and actions are passed on as they are
+pub struct Relay;
+
+impl GameLogic for Relay {}
+
+/// Hand the game logic everything that happened to players since the last tick, then run its tick
+pub fn run_tick(logic: &mut dyn GameLogic, session: &mut SessionStruct, tick: u64, tick_length: Duration) {
+    for event in std::mem::take(&mut session.player_events) {
+        match event {
+            PlayerEvent::Joined(player_id) => logic.player_joined(session, player_id),
+            PlayerEvent::Left(player_id) => logic.player_left(session, player_id),
+        }
+    }
+    logic.tick(session, tick, tick_length);
+}
@@ -0,0 +1,63 @@
+use std::sync::{Arc, atomic::{AtomicU64, AtomicI64, Ordering}};
+
+use game_structs::metrics::{write_metric, MetricKind};
+use rocket::{get, State};
+
+use crate::Session;
+
+/// Counters and gauges exposed on the /metrics endpoint
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub packets_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub send_errors: AtomicU64,
+    pub receive_errors: AtomicU64,
+    pub outbox_dropped: AtomicU64,
+    pub queue_full: AtomicU64,
+    pub channel_backlog: AtomicI64,
+    pub interest_filtered: AtomicU64,
+    pub ticks: AtomicU64,
+    pub rejected_malformed: AtomicU64,
+    pub rejected_unknown_player: AtomicU64,
+    pub rejected_bad_key: AtomicU64,
+    pub violations_speed: AtomicU64,
+    pub violations_bounds: AtomicU64,
+    pub violations_region: AtomicU64,
+    pub players_timed_out: AtomicU64,
+    pub players_unreachable: AtomicU64,
+    pub peer_updates_in: AtomicU64,
+    pub rejected_bad_peer: AtomicU64,
+}
+
+#[get("/metrics")]
+pub fn get_metrics(session: &State<Session>, metrics: &State<Arc<Metrics>>) -> String {
+    let mut out = String::new();
+    let (players, sequences) = {
+        let session = session.read().unwrap();
+        (session.players.len(), session.sequences.stats)
+    };
+    write_metric(&mut out, "server_registered_players", "Players registered on this server", MetricKind::Gauge, players);
+    write_metric(&mut out, "server_udp_packets_received_total", "UDP packets received from clients", MetricKind::Counter, metrics.packets_in.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_udp_packets_sent_total", "UDP packets sent to clients", MetricKind::Counter, metrics.packets_out.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_udp_send_errors_total", "UDP sends that failed", MetricKind::Counter, metrics.send_errors.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_udp_receive_errors_total", "UDP receives that failed", MetricKind::Counter, metrics.receive_errors.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_outbox_dropped_total", "Datagrams dropped because a client's outbox was full", MetricKind::Counter, metrics.outbox_dropped.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_queue_full_total", "Updates dropped because too many were waiting to be sent out", MetricKind::Counter, metrics.queue_full.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_channel_backlog", "Updates waiting to be sent out", MetricKind::Gauge, metrics.channel_backlog.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_interest_filtered_total", "Updates not sent because the recipient was too far away", MetricKind::Counter, metrics.interest_filtered.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_ticks_total", "Ticks the server has run", MetricKind::Counter, metrics.ticks.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_rejected_malformed_total", "UDP packets dropped because they couldn't be decoded", MetricKind::Counter, metrics.rejected_malformed.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_rejected_unknown_player_total", "Updates dropped because the player isn't registered here", MetricKind::Counter, metrics.rejected_unknown_player.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_rejected_bad_key_total", "Updates dropped because the session key didn't match the player", MetricKind::Counter, metrics.rejected_bad_key.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_violations_speed_total", "Moves clamped for going faster than the speed limit", MetricKind::Counter, metrics.violations_speed.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_violations_bounds_total", "Moves clamped for leaving the world", MetricKind::Counter, metrics.violations_bounds.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_violations_region_total", "Moves rejected for leaving this server's region", MetricKind::Counter, metrics.violations_region.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_players_timed_out_total", "Players unregistered for going quiet", MetricKind::Counter, metrics.players_timed_out.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_players_unreachable_total", "Players unregistered for not acking reliable events", MetricKind::Counter, metrics.players_unreachable.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_peer_updates_received_total", "Updates taken in from players near the border of neighbouring servers", MetricKind::Counter, metrics.peer_updates_in.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_rejected_bad_peer_total", "Peer updates dropped because their signature didn't check out or they were older than ones already received", MetricKind::Counter, metrics.rejected_bad_peer.load(Ordering::Relaxed));
+    write_metric(&mut out, "server_updates_accepted_total", "Position updates accepted from players", MetricKind::Counter, sequences.accepted);
+    write_metric(&mut out, "server_updates_stale_total", "Position updates dropped because a newer one from the player had already arrived", MetricKind::Counter, sequences.stale);
+    write_metric(&mut out, "server_updates_skipped_total", "Position updates from players that were skipped over, because they were lost or arrived after a newer one", MetricKind::Counter, sequences.skipped);
+    out
+}
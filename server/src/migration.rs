@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use game_structs::{Player, Vec3, auth::{AuthSecret, AuthToken, ServiceAuth, AUTH_HEADER}, operations::PlayerTransfer};
+use rocket::{post, http::Status, serde::json::Json, State};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{Session, SessionStruct, PlayerState, validation::ViolationReport};
+
+static INCOMING_TIMEOUT: Duration = Duration::from_secs(30); // How long to keep a migrated player's state for them to register
+
+/// Everything a server knows about a player, passed on to the server they are moving to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerMigration {
+    pub player: Player,
+    /// Latest accepted position, if they had reported in
+    pub position: Option<Vec3>,
+    pub seq: u32,
+    pub timestamp: u64,
+    pub violations: Option<ViolationReport>,
+}
+
+impl PlayerMigration {
+    /// Gather what we know about a player
+    pub fn export(session: &SessionStruct, player_id: &Uuid) -> Option<Self> {
+        let player = session.players.get(player_id)?.clone();
+        let state = session.states.get(player_id);
+        Some(Self {
+            player,
+            position: state.map(|s| s.position),
+            seq: state.map_or(0, |s| s.seq),
+            timestamp: state.map_or(0, |s| s.timestamp),
+            violations: session.violations.get(player_id).cloned(),
+        })
+    }
+
+    /// Restore a player's state on this server, they still need to register for an address and session key
+    pub fn import(self, session: &mut SessionStruct) {
+        let player_id = self.player.id;
+        session.players.insert(player_id, self.player);
+        if let Some(position) = self.position {
+            // The old server already checked this move, so it is where the speed limit starts from
+            session.states.insert(player_id, PlayerState { position, seq: self.seq, timestamp: self.timestamp, updated: Instant::now() });
+            session.grid.update(player_id, position);
+            session.sequences.remove(&player_id);
+            session.sequences.accept(player_id, self.seq);
+        }
+        if let Some(violations) = self.violations {
+            session.violations.insert(player_id, violations);
+        }
+    }
+}
+
+/// Take in a player from another server. Once this returns the old server lets the player go
+#[post("/migrate_player", format = "json", data = "<migration>")]
+pub fn migrate_player(session: &State<Session>, _auth: ServiceAuth, migration: Json<PlayerMigration>) -> Status {
+    let mut session = session.write().unwrap();
+    let now = Instant::now();
+    session.incoming.retain(|_, (_, received)| now.duration_since(*received) < INCOMING_TIMEOUT);
+    if session.players.contains_key(&migration.player.id) {
+        migration.into_inner().import(&mut session); // Registered before the state got here
+    } else {
+        session.incoming.insert(migration.player.id, (migration.into_inner(), now));
+    }
+    Status::Ok
+}
+
+/// Pass a player's state to the server they are moving to, and let them go once it has it.
+/// Called by the player as they leave, instead of /unregister_player
+#[post("/transfer_player", format = "json", data = "<transfer>")]
+pub async fn transfer_player(session: &State<Session>, secret: &State<AuthSecret>, token: AuthToken, transfer: Json<PlayerTransfer>) -> Status {
+    if !token.is_player(transfer.player_id) {
+        return Status::Unauthorized;
+    }
+    let (migration, address) = {
+        let session = session.read().unwrap();
+        let migration = match PlayerMigration::export(&session, &transfer.player_id) {
+            Some(m) => m,
+            None => return Status::NotFound,
+        };
+        let address = session.ownership.as_ref()
+            .and_then(|o| o.servers.get(transfer.to).cloned().flatten());
+        match address {
+            Some(a) => (migration, a.http),
+            None => return Status::ServiceUnavailable, // Don't know where that server is
+        }
+    };
+
+    let acked = reqwest::Client::new().post(format!("{}/migrate_player", address))
+        .header(AUTH_HEADER, &secret.0)
+        .json(&migration)
+        .send().await
+        .and_then(|r| r.error_for_status());
+    if acked.is_err() {
+        return Status::BadGateway; // Keep the player, they can try again or just leave
+    }
+    session.write().unwrap().remove_player(&transfer.player_id);
+    Status::Ok
+}
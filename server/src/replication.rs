@@ -0,0 +1,145 @@
+use std::{collections::HashMap, sync::atomic::Ordering, time::{Duration, Instant}};
+
+use game_structs::{Vec3, attributes::Attributes, auth::AuthSecret, operations::{ClientMessage, PeerUpdate, PositionUpdate, GameEvent, EVENT_CHANNEL}, reliable::Delivery};
+use uuid::Uuid;
+
+use crate::{SessionStruct, metrics::Metrics};
+
+static GHOST_TIMEOUT: Duration = Duration::from_secs(1); // Forget players on other servers we haven't had an update for in this long
+static PEER_BATCH_SIZE: usize = 20; // Updates per peer datagram, 48 bytes each plus about 120 for the rest keeps them under a typical 1200 byte MTU
+static ATTRIBUTE_REFRESH_TICKS: u64 = 20; // Ticks between sending neighbours the attributes of our players near their border
+static ATTRIBUTE_BUDGET: u64 = 900; // Bytes of attributes per peer datagram, leaving room for the rest under a typical 1200 byte MTU
+
+/// A player on a neighbouring server who is close enough to the border for our players to see
+#[derive(Debug, Clone)]
+pub struct Ghost {
+    pub position: Vec3,
+    pub server: usize,
+    pub updated: Instant,
+    pub attributes: Attributes,
+}
+
+/// What a peer update's signature covers
+fn signed_part(peer: &PeerUpdate) -> Vec<u8> {
+    bincode::serialize(&(peer.server, peer.started, peer.tick, &peer.updates, &peer.attributes)).unwrap()
+}
+
+/// Put players' attributes into groups small enough to fit in a datagram, anyone with more than that is left out
+fn attribute_batches(session: &SessionStruct, players: &[Uuid]) -> Vec<Vec<(Uuid, Attributes)>> {
+    let mut batches = vec![];
+    let (mut batch, mut size) = (vec![], 0);
+    for player_id in players {
+        let attributes = match session.players.get(player_id) {
+            Some(p) if !p.attributes.is_empty() => (*player_id, p.attributes.clone()),
+            _ => continue,
+        };
+        let attributes_size = bincode::serialized_size(&attributes).unwrap();
+        if attributes_size > ATTRIBUTE_BUDGET {continue;}
+        if size + attributes_size > ATTRIBUTE_BUDGET {
+            batches.push(std::mem::take(&mut batch));
+            size = 0;
+        }
+        batch.push(attributes);
+        size += attributes_size;
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Updates from our own players near a border, batched up for each neighbouring server that should also see them
+pub fn peer_messages<'a>(session: &SessionStruct, secret: &AuthSecret, started: u64, tick: u64, updates: impl Iterator<Item = &'a PositionUpdate>) -> Vec<(String, ClientMessage)> {
+    let ownership = match &session.ownership {
+        Some(o) => o,
+        None => return vec![], // Don't know who our neighbours are yet
+    };
+    let mut by_server: HashMap<usize, Vec<PositionUpdate>> = HashMap::new();
+    for update in updates.filter(|u| session.players.contains_key(&u.player_id)) {
+        for server in ownership.routing.query(update.position) {
+            if server != ownership.index {
+                by_server.entry(server).or_default().push(update.clone());
+            }
+        }
+    }
+
+    let mut messages = vec![];
+    for (server, updates) in by_server {
+        let address = match ownership.servers.get(server).and_then(|a| a.as_ref()) {
+            Some(a) => &a.udp,
+            None => continue,
+        };
+        let attributes = match tick % ATTRIBUTE_REFRESH_TICKS {
+            0 => attribute_batches(session, &updates.iter().map(|u| u.player_id).collect::<Vec<_>>()),
+            _ => vec![],
+        };
+        let batches = updates.chunks(PEER_BATCH_SIZE).map(|batch| (batch.to_vec(), vec![]))
+            .chain(attributes.into_iter().map(|batch| (vec![], batch)));
+        for (updates, attributes) in batches {
+            let mut peer = PeerUpdate { server: ownership.index, started, tick, updates, attributes, signature: String::new() };
+            peer.signature = secret.sign(&signed_part(&peer));
+            messages.push((address.clone(), ClientMessage::Peer(peer)));
+        }
+    }
+    messages
+}
+
+/// Take in updates from a neighbouring server as ghosts, returning the ones to pass on to our players
+pub fn receive_peer(session: &mut SessionStruct, secret: &AuthSecret, metrics: &Metrics, peer: PeerUpdate) -> Vec<PositionUpdate> {
+    if !secret.verify(&signed_part(&peer), &peer.signature) {
+        metrics.rejected_bad_peer.fetch_add(1, Ordering::Relaxed);
+        return vec![];
+    }
+    // Only take updates from the latest tick we've seen from that server, or from after it restarted
+    let latest = session.peer_ticks.entry(peer.server).or_default();
+    if (peer.started, peer.tick) < *latest {
+        metrics.rejected_bad_peer.fetch_add(1, Ordering::Relaxed);
+        return vec![];
+    }
+    *latest = (peer.started, peer.tick);
+    let now = Instant::now();
+    let mut accepted = vec![];
+    for update in peer.updates {
+        // Players on this server are more up to date here than anywhere else
+        if session.players.contains_key(&update.player_id) {continue;}
+        if !session.sequences.accept(update.player_id, update.seq) {continue;}
+        let ghost = session.ghosts.entry(update.player_id)
+            .or_insert_with(|| Ghost { position: update.position, server: peer.server, updated: now, attributes: Attributes::new() });
+        ghost.position = update.position;
+        ghost.server = peer.server;
+        ghost.updated = now;
+        session.grid.update(update.player_id, update.position);
+        accepted.push(update);
+    }
+    // Tell our players when the attributes of someone across the border change, as their own server does
+    for (player_id, attributes) in peer.attributes {
+        let ghost = match session.ghosts.get_mut(&player_id) {
+            Some(g) => g,
+            None => continue, // Not near enough, or their updates haven't arrived yet
+        };
+        let changed: Attributes = attributes.iter()
+            .filter(|(name, value)| ghost.attributes.get(*name) != Some(value))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        ghost.attributes = attributes;
+        if !changed.is_empty() {
+            session.broadcast_event(&GameEvent::AttributesChanged { player_id, attributes: changed }, EVENT_CHANNEL, Delivery::Ordered);
+        }
+    }
+    metrics.peer_updates_in.fetch_add(accepted.len() as u64, Ordering::Relaxed);
+    accepted
+}
+
+/// Forget ghosts who have moved away from the border or left
+pub fn expire_ghosts(session: &mut SessionStruct) {
+    let now = Instant::now();
+    let expired: Vec<_> = session.ghosts.iter()
+        .filter(|(_, ghost)| now.duration_since(ghost.updated) > GHOST_TIMEOUT)
+        .map(|(player_id, _)| *player_id)
+        .collect();
+    for player_id in expired {
+        session.ghosts.remove(&player_id);
+        session.grid.remove(&player_id);
+        session.sequences.remove(&player_id);
+    }
+}
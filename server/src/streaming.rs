@@ -1,26 +1,382 @@
-use std::{sync::mpsc::{Sender, Receiver}, net::UdpSocket};
+use std::{sync::{Arc, atomic::Ordering}, collections::{HashMap, HashSet}, time::{Duration, Instant}};
 
-use crate::SessionStruct;
+use game_structs::{auth::AuthSecret, entities::EntityUpdate, encoding::{CompactEncoder, Encoding}, operations::{ClientMessage, ClientUpdate, PositionUpdate, ServerMessage, GameEvent, ACTION_CHANNEL, MAX_ACTION_LENGTH}, reliable::{ReliableMessage, Delivery}, sequence::timestamp_now};
+use serde::Serialize;
+use tokio::{net::UdpSocket, sync::{mpsc::{self, error::{TryRecvError, TrySendError}}, watch}, time::{self, MissedTickBehavior}};
+use uuid::Uuid;
 
-pub fn send_positions(session: std::sync::Arc<std::sync::RwLock<SessionStruct>>, receiver: Receiver<Vec<u8>>, socket: UdpSocket) {
-    // Get position update from queue
-    while let Ok(position_update) = receiver.recv() {
-        // Send position update to all recipients
-        for address in session.read().unwrap().addresses.values() {
-            socket.send_to(&position_update, address)
-                .expect("Error on send");
+use crate::{Session, SessionStruct, chat, metrics::Metrics, interest::InterestConfig, logic::{self, GameLogic, Logic}, replication, validation::{self, ValidationConfig, Violation}};
+
+static SNAPSHOT_BATCH_SIZE: usize = 24; // Updates per snapshot datagram, 48 bytes each keeps them under a typical 1200 byte MTU
+static ENTITY_BATCH_SIZE: usize = 20; // Entity updates per datagram, they are bigger than player updates
+pub static UPDATE_QUEUE_SIZE: usize = 4096; // Updates that can wait for the next tick before new ones are dropped
+static OUTBOX_SIZE: usize = 256; // Datagrams that can wait to go to one client before new ones are dropped
+static ACTION_RADIUS: f32 = 50.; // How far away players are told about an action
+
+/// Every client and neighbouring server's outbox, created when we first send to them.
+/// Each is sent by a task of its own so one slow or failing recipient can't hold up the rest
+struct Outboxes {
+    socket: Arc<UdpSocket>,
+    metrics: Arc<Metrics>,
+    outboxes: HashMap<String, mpsc::Sender<Vec<u8>>>, // Datagrams waiting to go to each address
+}
+
+impl Outboxes {
+    /// Queue a message for an address, dropping it if their outbox is full
+    fn send(&mut self, address: &str, message: &impl Serialize) {
+        let outbox = match self.outboxes.get(address) {
+            Some(o) => o,
+            None => {
+                let (queue, receiver) = mpsc::channel(OUTBOX_SIZE);
+                tokio::spawn(drain_outbox(self.socket.clone(), self.metrics.clone(), address.to_string(), receiver));
+                self.outboxes.entry(address.to_string()).or_insert(queue)
+            }
+        };
+        match outbox.try_send(bincode::serialize(message).unwrap()) {
+            Ok(_) => {},
+            Err(TrySendError::Full(_)) => {
+                self.metrics.outbox_dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(TrySendError::Closed(_)) => {
+                self.metrics.outbox_dropped.fetch_add(1, Ordering::Relaxed);
+                self.outboxes.remove(address); // Start a new one next time
+            }
+        }
+    }
+
+    /// Close the outboxes of players who have left and servers that have gone, their tasks finish once they have sent what's queued
+    fn retain(&mut self, session: &SessionStruct) {
+        let servers = session.ownership.iter().flat_map(|o| o.servers.iter().flatten());
+        let addresses: HashSet<&str> = session.addresses.values().map(|a| a.as_str())
+            .chain(servers.map(|s| s.udp.as_str()))
+            .collect();
+        self.outboxes.retain(|address, _| addresses.contains(address.as_str()));
+    }
+}
+
+/// Send everything put in an outbox until it is closed, errors only count against this client
+async fn drain_outbox(socket: Arc<UdpSocket>, metrics: Arc<Metrics>, address: String, mut receiver: mpsc::Receiver<Vec<u8>>) {
+    while let Some(datagram) = receiver.recv().await {
+        match socket.send_to(&datagram, address.as_str()).await {
+            Ok(_) => metrics.packets_out.fetch_add(1, Ordering::Relaxed),
+            Err(_) => metrics.send_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Every tick, gather the latest update from each player and send each client one snapshot of the players near them,
+/// and pass on players near a border to the neighbouring server. Runs until shutdown is signalled or the receiving side stops
+#[allow(clippy::too_many_arguments)]
+pub async fn send_positions(session: Session, mut receiver: mpsc::Receiver<PositionUpdate>, socket: Arc<UdpSocket>, metrics: Arc<Metrics>, interest: InterestConfig, tick_rate: f32, secret: AuthSecret, logic: Logic, mut shutdown: watch::Receiver<bool>) {
+    let tick_length = Duration::from_secs_f32(1. / tick_rate);
+    let mut ticker = time::interval(tick_length);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip); // Running behind, don't try to catch up
+    let mut tick: u64 = 0;
+    let started = timestamp_now(); // Tells neighbouring servers our ticks started again if we restart
+    let mut encoders: HashMap<Uuid, CompactEncoder> = HashMap::new(); // What each compact player has been sent
+    let mut outboxes = Outboxes { socket, metrics: metrics.clone(), outboxes: HashMap::new() };
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.changed() => return,
+        }
+        tick += 1;
+
+        // Get the latest update from each player out of the queue
+        let mut latest = HashMap::new();
+        loop {
+            match receiver.try_recv() {
+                Ok(position_update) => {
+                    metrics.channel_backlog.fetch_sub(1, Ordering::Relaxed);
+                    latest.insert(position_update.player_id, position_update);
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        metrics.ticks.fetch_add(1, Ordering::Relaxed);
+
+        // Run the game logic, send reliable events and acks, resend anything that hasn't been acked and send corrections, and see which entities moved
+        let entity_updates = {
+            let mut session = session.write().unwrap();
+            let session = &mut *session;
+            logic::run_tick(logic.lock().unwrap().as_mut(), session, tick, tick_length);
+            let now = Instant::now();
+            for (player_id, endpoint) in session.reliable.iter_mut() {
+                let (address, session_key) = match (session.addresses.get(player_id), session.session_keys.get(player_id)) {
+                    (Some(a), Some(k)) => (a, *k),
+                    _ => continue,
+                };
+                for message in endpoint.poll(now) {
+                    outboxes.send(address, &ServerMessage::Reliable { session_key, message });
+                }
+            }
+            // Players that stopped acking can't be sent events any more, so unregister them
+            let unreachable: Vec<Uuid> = session.reliable.iter()
+                .filter(|(_, endpoint)| endpoint.failed())
+                .map(|(player_id, _)| *player_id)
+                .collect();
+            for player_id in unreachable {
+                session.remove_player(&player_id);
+                metrics.players_unreachable.fetch_add(1, Ordering::Relaxed);
+            }
+            // Tell players whose moves were clamped where they really are
+            for (player_id, correction) in std::mem::take(&mut session.corrections) {
+                if let Some(address) = session.addresses.get(&player_id) {
+                    outboxes.send(address, &ServerMessage::Correction(correction));
+                }
+            }
+            // Tell players being handed off where to go
+            for (player_id, servers) in std::mem::take(&mut session.handoff_notices) {
+                if let Some(address) = session.addresses.get(&player_id) {
+                    outboxes.send(address, &ServerMessage::Handoff { servers });
+                }
+            }
+            replication::expire_ghosts(session);
+            session.entities.take_moved()
+        };
+
+        // Work out which updates each player is close enough to care about
+        let session = session.read().unwrap();
+        let mut snapshots: HashMap<Uuid, Vec<PositionUpdate>> = HashMap::new();
+        for (player_id, position_update) in &latest {
+            let mut sent = 0;
+            for (id, distance) in session.grid.nearby(position_update.position, interest.max_radius()) {
+                // The grid also has ghosts, who are sent their updates by their own server
+                if id != *player_id && session.addresses.contains_key(&id) && interest.wants(distance, tick) {
+                    snapshots.entry(id).or_default().push(position_update.clone());
+                    sent += 1;
+                }
+            }
+            metrics.interest_filtered.fetch_add(session.addresses.len().saturating_sub(1 + sent) as u64, Ordering::Relaxed);
+        }
+
+        // Send each player their snapshot, split into datagrams that fit in the MTU
+        encoders.retain(|player_id, _| session.encodings.get(player_id) == Some(&Encoding::Compact)); // Forget players who left
+        for (player_id, updates) in snapshots {
+            if session.encodings.get(&player_id) == Some(&Encoding::Compact) {
+                // Compact players are sent everything at once below
+                let encoder = encoders.entry(player_id).or_default();
+                for update in updates {
+                    encoder.update(update.player_id, session.quantizer.quantize(update.position), update.seq);
+                }
+                continue;
+            }
+            let address = match session.addresses.get(&player_id) {
+                Some(a) => a,
+                None => continue,
+            };
+            for batch in updates.chunks(SNAPSHOT_BATCH_SIZE) {
+                outboxes.send(address, &ServerMessage::Snapshot(batch.to_vec()));
+            }
+        }
+
+        // Compact players get whatever changed since their last acknowledged snapshot, resent until they acknowledge it
+        for (player_id, encoder) in encoders.iter_mut() {
+            let (address, session_key) = match (session.addresses.get(player_id), session.session_keys.get(player_id)) {
+                (Some(a), Some(k)) => (a, *k),
+                _ => continue,
+            };
+            for snapshot in encoder.encode(session_key, session.acks.get(player_id).copied(), timestamp_now()) {
+                outboxes.send(address, &ServerMessage::Compact(snapshot));
+            }
+        }
+
+        // Players near entities that moved get their new transforms
+        let mut entity_snapshots: HashMap<Uuid, Vec<EntityUpdate>> = HashMap::new();
+        for update in entity_updates {
+            for (id, distance) in session.grid.nearby(update.position, interest.max_radius()) {
+                if session.addresses.contains_key(&id) && interest.wants(distance, tick) {
+                    entity_snapshots.entry(id).or_default().push(update.clone());
+                }
+            }
         }
+        for (player_id, updates) in entity_snapshots {
+            let address = match session.addresses.get(&player_id) {
+                Some(a) => a,
+                None => continue,
+            };
+            for batch in updates.chunks(ENTITY_BATCH_SIZE) {
+                outboxes.send(address, &ServerMessage::EntityUpdates(batch.to_vec()));
+            }
+        }
+
+        // Neighbouring servers get our players near their border
+        for (address, message) in replication::peer_messages(&session, &secret, started, tick, latest.values()) {
+            outboxes.send(&address, &message);
+        }
+        outboxes.retain(&session);
     }
 }
 
-pub fn receive_positions(sender: Sender<Vec<u8>>, socket: UdpSocket) {
+/// Check updates from clients and neighbouring servers and queue them for the next tick. Runs until shutdown is signalled
+#[allow(clippy::too_many_arguments)]
+pub async fn receive_positions(session: Session, sender: mpsc::Sender<PositionUpdate>, socket: UdpSocket, metrics: Arc<Metrics>, validation: ValidationConfig, secret: AuthSecret, logic: Logic, mut shutdown: watch::Receiver<bool>) {
+    let mut buf = [0; 2048];
     loop {
         // Wait till we receive an update
-        let mut buf = [0; 2048];
-        let (amt, _) = socket.recv_from(&mut buf)
-            .expect("Failed to receive");
-        // Put update into channel
-        sender.send(buf[..amt].to_vec())
-            .expect("Failed to send");
+        let amt = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((amt, _)) => amt,
+                Err(_) => {
+                    // Such as a client that has gone away, keep serving everyone else
+                    metrics.receive_errors.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => return,
+        };
+        metrics.packets_in.fetch_add(1, Ordering::Relaxed);
+        let message: ClientMessage = match bincode::deserialize(&buf[..amt]) {
+            Ok(m) => m,
+            Err(_) => {
+                // Not an update, drop it
+                metrics.rejected_malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        let position_update = {
+            let mut session = session.write().unwrap();
+            // Only accept updates from the player they claim to be from
+            let mut position_update = match message {
+                ClientMessage::Update(ClientUpdate { session_key, update }) => match session.session_keys.get(&update.player_id) {
+                    Some(key) if *key == session_key => {
+                        session.last_seen.insert(update.player_id, Instant::now());
+                        update
+                    },
+                    Some(_) => {
+                        metrics.rejected_bad_key.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    },
+                    None => {
+                        metrics.rejected_unknown_player.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                },
+                ClientMessage::Compact(update) => {
+                    // Compact updates leave out the player ID, the session key says who they are from
+                    let player_id = match session.key_owners.get(&update.session_key) {
+                        Some(player_id) => *player_id,
+                        None => {
+                            metrics.rejected_bad_key.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    };
+                    session.last_seen.insert(player_id, Instant::now());
+                    if let Some(ack) = update.ack {
+                        session.acks.insert(player_id, ack);
+                    }
+                    PositionUpdate { player_id, position: session.quantizer.dequantize(update.position), seq: update.seq, timestamp: update.timestamp }
+                },
+                ClientMessage::Reliable { session_key, message } => {
+                    match session.key_owners.get(&session_key) {
+                        Some(player_id) => {
+                            let player_id = *player_id;
+                            session.last_seen.insert(player_id, Instant::now());
+                            handle_reliable(&mut session, logic.lock().unwrap().as_mut(), player_id, message);
+                        },
+                        None => {
+                            metrics.rejected_bad_key.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    continue;
+                },
+                ClientMessage::Peer(peer) => {
+                    // Already checked by the server the players are on, signed by it so we know it was
+                    for update in replication::receive_peer(&mut session, &secret, &metrics, peer) {
+                        if !queue_update(&sender, &metrics, update) {return;}
+                    }
+                    continue;
+                }
+            };
+
+            // Drop updates that arrived after a newer one from the same player
+            if !session.sequences.accept(position_update.player_id, position_update.seq) {continue;}
+
+            // Make sure the move is legal, and note down players who break the rules
+            let reported = position_update.position;
+            let (position, violations) = validation::validate(&session, &validation, position_update.player_id, position_update.position);
+            for violation in &violations {
+                let counter = match violation {
+                    Violation::Speed => &metrics.violations_speed,
+                    Violation::Bounds => &metrics.violations_bounds,
+                    Violation::Region => &metrics.violations_region,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                session.violations.entry(position_update.player_id).or_default().record(*violation);
+            }
+            match position {
+                Some(p) => position_update.position = p,
+                None => continue,
+            }
+            // Let the game change or drop the update before anyone sees it
+            let position_update = match logic.lock().unwrap().player_update(&mut session, position_update) {
+                Some(u) => u,
+                None => continue,
+            };
+            if position_update.position != reported {
+                // Otherwise the client carries on from where it thinks it is and keeps breaking the rules
+                session.corrections.insert(position_update.player_id, position_update.clone());
+            }
+            session.update_state(&position_update);
+            position_update
+        };
+
+        if !queue_update(&sender, &metrics, position_update) {return;}
+    }
+}
+
+/// Put an update into the channel, dropping it if the sender can't keep up. Returns false if the sender has stopped
+fn queue_update(sender: &mpsc::Sender<PositionUpdate>, metrics: &Metrics, update: PositionUpdate) -> bool {
+    match sender.try_send(update) {
+        Ok(_) => {
+            metrics.channel_backlog.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(TrySendError::Full(_)) => {
+            metrics.queue_full.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(TrySendError::Closed(_)) => return false,
     }
-}
\ No newline at end of file
+    true
+}
+
+/// Pass a reliable message to the player's endpoint and act on any events it hands over
+fn handle_reliable(session: &mut SessionStruct, logic: &mut dyn GameLogic, player_id: Uuid, message: ReliableMessage) {
+    let payloads = match session.reliable.get_mut(&player_id) {
+        Some(endpoint) => endpoint.receive(message),
+        None => return,
+    };
+    for payload in payloads {
+        // Pass actions and chat on to everyone else, only the server says who joined or left.
+        // The events carry the player the channel belongs to, clients can't act or speak for other players
+        match bincode::deserialize(&payload) {
+            Ok(GameEvent::Action { action, .. }) => {
+                if action.len() > MAX_ACTION_LENGTH {continue;}
+                let action = match logic.player_action(session, player_id, action) {
+                    Some(a) if a.len() <= MAX_ACTION_LENGTH => a,
+                    _ => continue,
+                };
+                let position = match session.states.get(&player_id) {
+                    Some(state) => state.position,
+                    None => continue, // Hasn't reported in yet, so nobody is near them
+                };
+                let event = GameEvent::Action { player_id, action };
+                // The grid also has ghosts, who are told by their own server
+                let others: Vec<Uuid> = session.grid.nearby(position, ACTION_RADIUS).into_iter()
+                    .map(|(id, _)| id)
+                    .filter(|id| *id != player_id && session.reliable.contains_key(id))
+                    .collect();
+                for other in others {
+                    session.send_event(&other, &event, ACTION_CHANNEL, Delivery::Unordered);
+                }
+            },
+            Ok(GameEvent::Chat { scope, text, .. }) => {
+                if let Some(text) = logic.player_chat(session, player_id, scope, text) {
+                    chat::send_chat(session, player_id, scope, text);
+                }
+            },
+            _ => {},
+        }
+    }
+}
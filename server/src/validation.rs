@@ -0,0 +1,139 @@
+use std::{thread, time::{Duration, Instant}};
+
+use game_structs::{Aabb, ServerAddress, Vec3, auth::ServiceAuth, routing::{RoutingTable, RoutingUpdate}};
+use rocket::{get, State};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{Session, SessionStruct};
+
+static SPEED_TOLERANCE: f32 = 1.; // Extra distance allowed per update to cover network jitter
+static OWNERSHIP_REFRESH_INTERVAL: Duration = Duration::from_secs(5); // How often to download the whole routing table and server list
+static ROUTING_POLL_INTERVAL: Duration = Duration::from_millis(250); // How often to check for changes to the routing table in between
+
+/// Limits on how players can move
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationConfig {
+    /// Fastest a player can move, in units per second
+    pub max_speed: f32,
+    /// Players must stay inside this region
+    pub world: Aabb,
+}
+
+/// Which server this is, the routing table used to check a position is in its region,
+/// and where the other servers are so players near the border can be passed on to them
+#[derive(Debug, Clone)]
+pub struct Ownership {
+    pub index: usize,
+    pub routing: RoutingTable,
+    pub servers: Vec<Option<ServerAddress>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    /// Moved further than the speed limit allows, clamped
+    Speed,
+    /// Left the world, clamped
+    Bounds,
+    /// Moved outside the regions this server covers, rejected
+    Region,
+}
+
+/// How many times a player has broken each rule
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ViolationReport {
+    pub speed: u64,
+    pub bounds: u64,
+    pub region: u64,
+    pub last: Option<Violation>,
+}
+
+impl ViolationReport {
+    pub fn record(&mut self, violation: Violation) {
+        match violation {
+            Violation::Speed => self.speed += 1,
+            Violation::Bounds => self.bounds += 1,
+            Violation::Region => self.region += 1,
+        }
+        self.last = Some(violation);
+    }
+}
+
+/// Check a move against the player's last known state.
+/// Returns the position to accept, if any, and the rules that were broken
+pub fn validate(session: &SessionStruct, config: &ValidationConfig, player_id: Uuid, position: Vec3) -> (Option<Vec3>, Vec<Violation>) {
+    let mut violations = vec![];
+    let mut position = position;
+
+    // Speed limit, measured from the last accepted position
+    if let Some(state) = session.states.get(&player_id) {
+        let max_distance = config.max_speed * state.updated.elapsed().as_secs_f32() + SPEED_TOLERANCE;
+        let moved = position - state.position;
+        if moved.length() > max_distance {
+            position = state.position + moved.normalize() * max_distance;
+            violations.push(Violation::Speed);
+        }
+    }
+
+    // World bounds
+    let clamped = position.clamp(config.world.min, config.world.max);
+    if clamped != position {
+        position = clamped;
+        violations.push(Violation::Bounds);
+    }
+
+    // Region ownership, players being handed off are allowed to leave
+    if let Some(ownership) = &session.ownership {
+        if !session.handoffs.contains_key(&player_id) && !ownership.routing.query(position).contains(&ownership.index) {
+            violations.push(Violation::Region);
+            return (None, violations);
+        }
+    }
+
+    (Some(position), violations)
+}
+
+/// Keep track of which region this server owns by asking the coordination server.
+/// Changes to the table are picked up as soon as they are published, so players the coord has just moved here aren't rejected
+pub fn track_ownership(session: Session, coord_address: String, http_address: String) {
+    let client = reqwest::blocking::Client::new();
+    let mut refreshed: Option<Instant> = None; // When we last downloaded everything
+    loop {
+        if refreshed.is_none_or(|r| r.elapsed() >= OWNERSHIP_REFRESH_INTERVAL) {
+            let servers = client.get(format!("{}/get_servers", coord_address))
+                .send().and_then(|r| r.error_for_status()).and_then(|r| r.json::<Vec<Option<ServerAddress>>>());
+            let routing = client.get(format!("{}/get_routing", coord_address))
+                .send().and_then(|r| r.error_for_status()).and_then(|r| r.json::<RoutingTable>());
+            if let (Ok(servers), Ok(routing)) = (servers, routing) {
+                let index = servers.iter().position(|s| s.as_ref().is_some_and(|s| s.http == http_address));
+                session.write().unwrap().ownership = index.map(|index| Ownership { index, routing, servers });
+                refreshed = Some(Instant::now());
+            } // Otherwise keep what we had
+        } else {
+            let current = session.read().unwrap().ownership.as_ref().map(|o| (o.routing.generation, o.routing.epoch));
+            if let Some((generation, epoch)) = current {
+                let updates = client.get(format!("{}/get_routing_updates?generation={}&since={}", coord_address, generation, epoch))
+                    .send().and_then(|r| r.error_for_status()).and_then(|r| r.json::<Vec<RoutingUpdate>>());
+                let up_to_date = match updates {
+                    Ok(updates) if updates.is_empty() => true,
+                    Ok(updates) => match session.write().unwrap().ownership.as_mut() {
+                        Some(ownership) => updates.iter().all(|u| ownership.routing.apply(u)),
+                        None => false,
+                    },
+                    Err(_) => false, // Too far behind, or the coord restarted
+                };
+                if !up_to_date {
+                    refreshed = None; // Download everything next time round
+                }
+            }
+        }
+        thread::sleep(ROUTING_POLL_INTERVAL);
+    }
+}
+
+/// Players who have broken movement rules, for spotting cheaters
+#[get("/get_violations")]
+pub fn get_violations(session: &State<Session>, _auth: ServiceAuth) -> String {
+    serde_json::to_string(&session.read().unwrap().violations).unwrap()
+}